@@ -0,0 +1,184 @@
+//! SMTP AUTH support
+//!
+//! Decodes the PLAIN, LOGIN and CRAM-MD5 exchanges and checks credentials
+//! against the configured user list, or accepts any credentials.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// SASL mechanisms advertised in the EHLO response
+pub const MECHANISMS: &str = "PLAIN LOGIN CRAM-MD5";
+
+/// How SMTP AUTH credentials are checked
+#[derive(Debug, Clone, Default)]
+pub enum AuthMode {
+    /// Accept any username and password
+    #[default]
+    AcceptAny,
+    /// Only accept the configured usernames with their passwords
+    Users(HashMap<String, String>),
+}
+
+impl AuthMode {
+    /// Build the user list mode from `username:password` entries
+    ///
+    /// Returns `None` if an entry has no `:` separator.
+    pub fn from_user_list(entries: &[String]) -> Option<Self> {
+        let users = entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .split_once(':')
+                    .map(|(user, password)| (user.to_string(), password.to_string()))
+            })
+            .collect::<Option<HashMap<_, _>>>()?;
+        Some(AuthMode::Users(users))
+    }
+
+    /// Check a username and cleartext password (PLAIN and LOGIN)
+    pub fn check_password(&self, username: &str, password: &str) -> bool {
+        match self {
+            AuthMode::AcceptAny => true,
+            AuthMode::Users(users) => users.get(username).is_some_and(|p| p == password),
+        }
+    }
+
+    /// Check a CRAM-MD5 digest computed by the client over `challenge`
+    pub fn check_cram_md5(&self, username: &str, challenge: &str, digest: &str) -> bool {
+        match self {
+            AuthMode::AcceptAny => true,
+            AuthMode::Users(users) => users
+                .get(username)
+                .is_some_and(|password| cram_md5_digest(password, challenge) == digest),
+        }
+    }
+}
+
+/// Decode a base64 encoded SASL response
+///
+/// A lone `=` stands for an empty initial response (RFC 4954).
+pub fn decode(data: &str) -> Option<Vec<u8>> {
+    if data == "=" {
+        return Some(Vec::new());
+    }
+    BASE64.decode(data.trim()).ok()
+}
+
+/// Decode a base64 encoded SASL response as UTF-8 text
+pub fn decode_text(data: &str) -> Option<String> {
+    decode(data).and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+/// Encode a server challenge for a `334` reply
+pub fn encode(data: &str) -> String {
+    BASE64.encode(data)
+}
+
+/// Parse an AUTH PLAIN response into the username and password
+///
+/// The message is `[authzid] NUL authcid NUL passwd` (RFC 4616); the authorization
+/// identity is ignored.
+pub fn parse_plain(data: &str) -> Option<(String, String)> {
+    let text = decode_text(data)?;
+    let mut fields = text.split('\0');
+    let _authzid = fields.next()?;
+    let username = fields.next()?;
+    let password = fields.next()?;
+    if fields.next().is_some() || username.is_empty() {
+        return None;
+    }
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Create a fresh CRAM-MD5 challenge
+pub fn cram_md5_challenge() -> String {
+    format!("<{}@mailhits>", Uuid::new_v4().simple())
+}
+
+/// Parse a CRAM-MD5 response into the username and hex digest
+pub fn parse_cram_md5(data: &str) -> Option<(String, String)> {
+    let text = decode_text(data)?;
+    let (username, digest) = text.rsplit_once(' ')?;
+    if username.is_empty() {
+        return None;
+    }
+    Some((username.to_string(), digest.to_ascii_lowercase()))
+}
+
+/// Compute the lowercase hex HMAC-MD5 digest of a challenge (RFC 2195)
+pub fn cram_md5_digest(password: &str, challenge: &str) -> String {
+    let mut mac =
+        Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(challenge.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain() {
+        let data = encode("\0alice\0secret");
+        assert_eq!(
+            parse_plain(&data),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+
+        let data = encode("admin\0alice\0secret");
+        assert_eq!(parse_plain(&data).unwrap().0, "alice");
+
+        assert!(parse_plain(&encode("alice")).is_none());
+        assert!(parse_plain("not base64!").is_none());
+    }
+
+    #[test]
+    fn test_cram_md5_digest() {
+        // Example from RFC 2195
+        let digest = cram_md5_digest(
+            "tanstaaftanstaaf",
+            "<1896.697170952@postoffice.reston.mci.net>",
+        );
+        assert_eq!(digest, "b913a602c7eda7a495b4e6e7334d3890");
+
+        let response = encode("tim b913a602c7eda7a495b4e6e7334d3890");
+        assert_eq!(
+            parse_cram_md5(&response),
+            Some((
+                "tim".to_string(),
+                "b913a602c7eda7a495b4e6e7334d3890".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_auth_mode() {
+        assert!(AuthMode::AcceptAny.check_password("anyone", "anything"));
+        assert!(AuthMode::AcceptAny.check_cram_md5("anyone", "<x@y>", "00"));
+
+        let mode = AuthMode::from_user_list(&["alice:secret".to_string()]).unwrap();
+        assert!(mode.check_password("alice", "secret"));
+        assert!(!mode.check_password("alice", "wrong"));
+        assert!(!mode.check_password("bob", "secret"));
+
+        let challenge = "<1@mailhits>";
+        let digest = cram_md5_digest("secret", challenge);
+        assert!(mode.check_cram_md5("alice", challenge, &digest));
+        assert!(!mode.check_cram_md5("alice", "<2@mailhits>", &digest));
+
+        assert!(AuthMode::from_user_list(&["missing-separator".to_string()]).is_none());
+    }
+
+    #[test]
+    fn test_decode_empty_initial_response() {
+        assert_eq!(decode("="), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,264 @@
+//! iCalendar invitation parsing
+//!
+//! Meeting invitations, updates and cancellations (iTIP, RFC 5546) are sent as a
+//! `text/calendar` part, often alongside an `application/ics` attachment with the same
+//! content. The first such part is parsed into the events it describes.
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use ical::parser::ical::component::IcalEvent;
+use ical::property::Property;
+use mail_parser::{Message, MimeHeaders};
+
+use crate::models::{Calendar, CalendarAttendee, CalendarEvent, CalendarTime};
+
+/// Parse the calendar carried by an email, or return `None` if it has none
+pub fn parse_calendar(message: &Message) -> Option<Calendar> {
+    let part = message.parts.iter().find(|part| {
+        part.content_type().is_some_and(|ct| {
+            let subtype = ct.subtype().unwrap_or_default();
+            (ct.ctype().eq_ignore_ascii_case("text") && subtype.eq_ignore_ascii_case("calendar"))
+                || (ct.ctype().eq_ignore_ascii_case("application")
+                    && subtype.eq_ignore_ascii_case("ics"))
+        })
+    })?;
+
+    let contents = String::from_utf8_lossy(part.contents());
+    let calendar = match ical::IcalParser::new(contents.as_bytes()).next()? {
+        Ok(calendar) => calendar,
+        Err(_) => return None,
+    };
+
+    // The method parameter of the content type must match the METHOD property,
+    // but is the only one present in some invitations
+    let method = property(&calendar.properties, "METHOD")
+        .and_then(|method| method.value.clone())
+        .or_else(|| {
+            part.content_type()
+                .and_then(|ct| ct.attribute("method"))
+                .map(str::to_string)
+        })
+        .map(|method| method.to_ascii_uppercase());
+
+    Some(Calendar {
+        method,
+        events: calendar.events.iter().map(parse_event).collect(),
+    })
+}
+
+/// Extract the properties of a `VEVENT`
+fn parse_event(event: &IcalEvent) -> CalendarEvent {
+    let value = |name: &str| property(&event.properties, name).and_then(|p| p.value.clone());
+
+    CalendarEvent {
+        uid: value("UID"),
+        summary: value("SUMMARY").map(|summary| unescape_text(&summary)),
+        location: value("LOCATION").map(|location| unescape_text(&location)),
+        status: value("STATUS").map(|status| status.to_ascii_uppercase()),
+        sequence: value("SEQUENCE").and_then(|sequence| sequence.trim().parse().ok()),
+        start: property(&event.properties, "DTSTART").and_then(parse_time),
+        end: property(&event.properties, "DTEND").and_then(parse_time),
+        organizer: property(&event.properties, "ORGANIZER").and_then(parse_attendee),
+        attendees: event
+            .properties
+            .iter()
+            .filter(|p| p.name.eq_ignore_ascii_case("ATTENDEE"))
+            .filter_map(parse_attendee)
+            .collect(),
+    }
+}
+
+/// Parse a `DTSTART` or `DTEND` property
+fn parse_time(property: &Property) -> Option<CalendarTime> {
+    let value = property.value.as_deref()?.trim();
+    let all_day = param(property, "VALUE").is_some_and(|kind| kind.eq_ignore_ascii_case("DATE"))
+        || !value.contains('T');
+    let utc = value
+        .strip_suffix('Z')
+        .and_then(|local| NaiveDateTime::parse_from_str(local, "%Y%m%dT%H%M%S").ok())
+        .map(|time| Utc.from_utc_datetime(&time));
+
+    Some(CalendarTime {
+        value: value.to_string(),
+        tzid: param(property, "TZID").map(str::to_string),
+        all_day,
+        utc,
+    })
+}
+
+/// Parse an `ORGANIZER` or `ATTENDEE` property
+fn parse_attendee(property: &Property) -> Option<CalendarAttendee> {
+    let value = property.value.as_deref()?.trim();
+    let address = match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+        _ => value,
+    };
+
+    Some(CalendarAttendee {
+        address: address.to_string(),
+        name: param(property, "CN").map(str::to_string),
+        role: param(property, "ROLE").map(|role| role.to_ascii_uppercase()),
+        partstat: param(property, "PARTSTAT").map(|partstat| partstat.to_ascii_uppercase()),
+        rsvp: param(property, "RSVP").is_some_and(|rsvp| rsvp.eq_ignore_ascii_case("TRUE")),
+    })
+}
+
+/// Find the first property with the given name
+fn property<'a>(properties: &'a [Property], name: &str) -> Option<&'a Property> {
+    properties
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// First value of a property parameter
+fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(param, _)| param.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// Undo the escaping of a TEXT value: `\\`, `\;`, `\,` and `\n`
+fn unescape_text(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => text.push('\n'),
+            Some(escaped) => text.push(escaped),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    #[test]
+    fn test_parse_calendar() {
+        let raw = b"From: booking@example.com\r\n\
+            Subject: Invitation: Planning\r\n\
+            Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            You are invited.\r\n\
+            --b\r\n\
+            Content-Type: text/calendar; charset=UTF-8; method=REQUEST\r\n\
+            \r\n\
+            BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            PRODID:-//Example//Booking//EN\r\n\
+            METHOD:REQUEST\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:event-1@example.com\r\n\
+            SEQUENCE:2\r\n\
+            SUMMARY:Planning\\, Q3\r\n\
+            LOCATION:Room 1\r\n\
+            DTSTART;TZID=Europe/Berlin:20240115T100000\r\n\
+            DTEND:20240115T100000Z\r\n\
+            ORGANIZER;CN=\"Booking, Inc.\":mailto:booking@example.com\r\n\
+            ATTENDEE;CN=Alice;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:\r\n\
+            \x20mailto:alice@example.com\r\n\
+            ATTENDEE;PARTSTAT=ACCEPTED:MAILTO:bob@example.com\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n\
+            --b--\r\n";
+        let message = MessageParser::default().parse(&raw[..]).unwrap();
+        let calendar = parse_calendar(&message).unwrap();
+
+        assert_eq!(calendar.method.as_deref(), Some("REQUEST"));
+        assert_eq!(calendar.events.len(), 1);
+        let event = &calendar.events[0];
+        assert_eq!(event.uid.as_deref(), Some("event-1@example.com"));
+        assert_eq!(event.sequence, Some(2));
+        assert_eq!(event.summary.as_deref(), Some("Planning, Q3"));
+        assert_eq!(event.location.as_deref(), Some("Room 1"));
+        assert_eq!(
+            event.start,
+            Some(CalendarTime {
+                value: "20240115T100000".to_string(),
+                tzid: Some("Europe/Berlin".to_string()),
+                all_day: false,
+                utc: None,
+            })
+        );
+        assert_eq!(
+            event.end.as_ref().and_then(|end| end.utc),
+            Some(Utc.with_ymd_and_hms(2024, 1, 15, 10, 0, 0).unwrap())
+        );
+        assert_eq!(
+            event.organizer,
+            Some(CalendarAttendee {
+                address: "booking@example.com".to_string(),
+                name: Some("Booking, Inc.".to_string()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            event.attendees,
+            vec![
+                CalendarAttendee {
+                    address: "alice@example.com".to_string(),
+                    name: Some("Alice".to_string()),
+                    role: Some("REQ-PARTICIPANT".to_string()),
+                    partstat: Some("NEEDS-ACTION".to_string()),
+                    rsvp: true,
+                },
+                CalendarAttendee {
+                    address: "bob@example.com".to_string(),
+                    partstat: Some("ACCEPTED".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_calendar_cancel_attachment() {
+        // Method only on the content type, all-day event in an .ics attachment
+        let raw = b"Subject: Cancelled: Offsite\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Cancelled.\r\n\
+            --b\r\n\
+            Content-Type: application/ics; method=cancel; name=\"invite.ics\"\r\n\
+            Content-Disposition: attachment; filename=\"invite.ics\"\r\n\
+            \r\n\
+            BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:offsite@example.com\r\n\
+            STATUS:CANCELLED\r\n\
+            DTSTART;VALUE=DATE:20240301\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n\
+            --b--\r\n";
+        let message = MessageParser::default().parse(&raw[..]).unwrap();
+        let calendar = parse_calendar(&message).unwrap();
+
+        assert_eq!(calendar.method.as_deref(), Some("CANCEL"));
+        let event = &calendar.events[0];
+        assert_eq!(event.status.as_deref(), Some("CANCELLED"));
+        assert!(event.start.as_ref().unwrap().all_day);
+        assert!(event.end.is_none());
+
+        let plain = MessageParser::default()
+            .parse(&b"Subject: Hello\r\n\r\nHello\r\n"[..])
+            .unwrap();
+        assert!(parse_calendar(&plain).is_none());
+    }
+}
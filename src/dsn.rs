@@ -0,0 +1,185 @@
+//! Delivery status notification (DSN) parsing
+//!
+//! A DSN (RFC 3464) is a `multipart/report; report-type=delivery-status` message whose
+//! `message/delivery-status` part holds a group of per-message fields followed by one
+//! group of fields for each recipient, separated by blank lines.
+
+use mail_parser::{Message, MimeHeaders};
+
+use crate::models::{Dsn, DsnRecipient};
+
+/// Parse the delivery status of a DSN, or return `None` if the message is not a DSN
+pub fn parse_dsn(message: &Message) -> Option<Dsn> {
+    let root = message.root_part().content_type()?;
+    let is_report = root.ctype().eq_ignore_ascii_case("multipart")
+        && root
+            .subtype()
+            .is_some_and(|subtype| subtype.eq_ignore_ascii_case("report"))
+        && root
+            .attribute("report-type")
+            .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"));
+    if !is_report {
+        return None;
+    }
+
+    // `message/global-delivery-status` is the internationalized variant (RFC 6533)
+    let status = message.parts.iter().find(|part| {
+        part.content_type().is_some_and(|ct| {
+            ct.ctype().eq_ignore_ascii_case("message")
+                && ct.subtype().is_some_and(|subtype| {
+                    subtype.eq_ignore_ascii_case("delivery-status")
+                        || subtype.eq_ignore_ascii_case("global-delivery-status")
+                })
+        })
+    })?;
+
+    let mut groups = parse_field_groups(&String::from_utf8_lossy(status.contents())).into_iter();
+    let per_message = groups.next().unwrap_or_default();
+    let field = |fields: &[(String, String)], name: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+
+    Some(Dsn {
+        reporting_mta: field(&per_message, "Reporting-MTA"),
+        original_envelope_id: field(&per_message, "Original-Envelope-Id"),
+        arrival_date: field(&per_message, "Arrival-Date"),
+        recipients: groups
+            .map(|fields| DsnRecipient {
+                final_recipient: field(&fields, "Final-Recipient").map(|value| strip_type(&value)),
+                original_recipient: field(&fields, "Original-Recipient")
+                    .map(|value| strip_type(&value)),
+                action: field(&fields, "Action").map(|value| value.to_ascii_lowercase()),
+                status: field(&fields, "Status"),
+                diagnostic_code: field(&fields, "Diagnostic-Code"),
+                remote_mta: field(&fields, "Remote-MTA"),
+            })
+            .collect(),
+    })
+}
+
+/// Split the body of a delivery-status part into groups of unfolded `(name, value)` fields
+fn parse_field_groups(body: &str) -> Vec<Vec<(String, String)>> {
+    let mut groups = Vec::new();
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in body.lines() {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                groups.push(std::mem::take(&mut fields));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            // Continuation of a folded field
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    if !fields.is_empty() {
+        groups.push(fields);
+    }
+    groups
+}
+
+/// Remove the address type from a recipient field, such as `rfc822;` in `rfc822; user@example.com`
+fn strip_type(value: &str) -> String {
+    match value.split_once(';') {
+        Some((_, address)) => address.trim().to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    #[test]
+    fn test_parse_dsn() {
+        let raw = b"From: MAILER-DAEMON@example.com\r\n\
+            Subject: Undelivered Mail Returned to Sender\r\n\
+            Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Delivery failed.\r\n\
+            --b\r\n\
+            Content-Type: message/delivery-status\r\n\
+            \r\n\
+            Reporting-MTA: dns; mx.example.com\r\n\
+            Original-Envelope-Id: envelope-1\r\n\
+            Arrival-Date: Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+            \r\n\
+            Final-Recipient: rfc822; missing@example.com\r\n\
+            Original-Recipient: rfc822;Missing@example.com\r\n\
+            Action: Failed\r\n\
+            Status: 5.1.1\r\n\
+            Remote-MTA: dns; mail.example.net\r\n\
+            Diagnostic-Code: smtp; 550 5.1.1 <missing@example.com>:\r\n\
+            \x20Recipient address rejected\r\n\
+            \r\n\
+            Final-Recipient: rfc822;late@example.com\r\n\
+            Action: delayed\r\n\
+            Status: 4.4.7\r\n\
+            --b\r\n\
+            Content-Type: text/rfc822-headers\r\n\
+            \r\n\
+            Subject: Original\r\n\
+            --b--\r\n";
+        let message = MessageParser::default().parse(&raw[..]).unwrap();
+        let dsn = parse_dsn(&message).unwrap();
+
+        assert_eq!(dsn.reporting_mta.as_deref(), Some("dns; mx.example.com"));
+        assert_eq!(dsn.original_envelope_id.as_deref(), Some("envelope-1"));
+        assert_eq!(
+            dsn.arrival_date.as_deref(),
+            Some("Mon, 1 Jan 2024 00:00:00 +0000")
+        );
+        assert_eq!(
+            dsn.recipients,
+            vec![
+                DsnRecipient {
+                    final_recipient: Some("missing@example.com".to_string()),
+                    original_recipient: Some("Missing@example.com".to_string()),
+                    action: Some("failed".to_string()),
+                    status: Some("5.1.1".to_string()),
+                    diagnostic_code: Some(
+                        "smtp; 550 5.1.1 <missing@example.com>: Recipient address rejected"
+                            .to_string()
+                    ),
+                    remote_mta: Some("dns; mail.example.net".to_string()),
+                },
+                DsnRecipient {
+                    final_recipient: Some("late@example.com".to_string()),
+                    original_recipient: None,
+                    action: Some("delayed".to_string()),
+                    status: Some("4.4.7".to_string()),
+                    diagnostic_code: None,
+                    remote_mta: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dsn_ignores_other_messages() {
+        let other_report = b"Content-Type: multipart/report; report-type=disposition-notification; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Read.\r\n\
+            --b--\r\n";
+        let plain = b"Subject: Hello\r\n\r\nHello\r\n";
+
+        for raw in [&other_report[..], &plain[..]] {
+            let message = MessageParser::default().parse(raw).unwrap();
+            assert!(parse_dsn(&message).is_none());
+        }
+    }
+}
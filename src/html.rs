@@ -0,0 +1,186 @@
+//! Rendering of HTML bodies for the preview and text extraction
+//!
+//! Captured HTML is untrusted, so it is sanitized before it is served from the MailHits
+//! origin: scripts, event handlers and dangerous URLs are stripped, and the response
+//! carries a Content-Security-Policy that blocks remote loads unless explicitly allowed.
+//!
+//! Inline images are referenced from the HTML with `cid:` URLs (RFC 2392), which a
+//! browser cannot load. They are rewritten to the endpoint serving parts by Content-ID.
+
+use ammonia::Builder;
+use mail_parser::decoders::html::html_to_text;
+
+/// Tags allowed in addition to ammonia's defaults, commonly used for email layout
+const EXTRA_TAGS: &[&str] = &["font", "style", "tfoot"];
+
+/// Presentational attributes allowed on every tag
+const EXTRA_ATTRIBUTES: &[&str] = &[
+    "align",
+    "background",
+    "bgcolor",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "class",
+    "color",
+    "dir",
+    "face",
+    "height",
+    "id",
+    "size",
+    "style",
+    "valign",
+    "width",
+];
+
+/// Sanitize an HTML body and wrap it in a standalone document
+///
+/// Scripts, event handler attributes, forms, frames and URLs with unsafe schemes
+/// are removed; styles are kept so that the layout is preserved.
+pub fn render_document(html: &str) -> String {
+    let body = Builder::default()
+        .add_tags(EXTRA_TAGS)
+        .rm_clean_content_tags(&["style"])
+        .add_generic_attributes(EXTRA_ATTRIBUTES)
+        .add_url_schemes(&["data"])
+        .clean(html)
+        .to_string();
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        body
+    )
+}
+
+/// Content-Security-Policy for rendered HTML bodies
+///
+/// Only inline styles and images from MailHits itself or `data:` URLs are allowed,
+/// plus remote images when `remote_images` is set. Scripts never run.
+pub fn content_security_policy(remote_images: bool) -> String {
+    let img_src = if remote_images {
+        "'self' data: http: https:"
+    } else {
+        "'self' data:"
+    };
+    format!(
+        "default-src 'none'; img-src {}; style-src 'unsafe-inline'; font-src data:; \
+         base-uri 'none'; form-action 'none'; frame-ancestors 'self'",
+        img_src
+    )
+}
+
+/// Extract the text of an HTML body, for snippets and the search index
+///
+/// Every tag separates words, so that the text of adjacent blocks such as
+/// `<h1>Hello</h1><div>World</div>` does not run together.
+pub fn to_text(html: &str) -> String {
+    html_to_text(&html.replace('<', " <"))
+}
+
+/// Rewrite the `cid:` URLs in attribute values and CSS `url()`s to `base/<content-id>`
+///
+/// The Content-ID is kept URL-encoded as written in the HTML, except that `/` is
+/// escaped so that it stays a single path segment.
+pub fn rewrite_cid_urls(html: &str, base: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = find_cid_url(rest) {
+        let value = &rest[start + 4..];
+        let end = value
+            .find(|c: char| matches!(c, '"' | '\'' | '(' | ')' | '<' | '>') || c.is_whitespace())
+            .unwrap_or(value.len());
+
+        result.push_str(&rest[..start]);
+        result.push_str(base);
+        result.push('/');
+        result.push_str(&value[..end].replace('/', "%2F"));
+        rest = &value[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Find the next `cid:` that starts an attribute value or a CSS `url()`
+fn find_cid_url(html: &str) -> Option<usize> {
+    let bytes = html.as_bytes();
+    (0..bytes.len().saturating_sub(3)).find(|&i| {
+        bytes[i..i + 4].eq_ignore_ascii_case(b"cid:")
+            && i > 0
+            && matches!(bytes[i - 1], b'"' | b'\'' | b'=' | b'(')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_cid_urls() {
+        let base = "/api/emails/email-1/cid";
+        let html = "<img src=\"cid:logo@example.com\">\
+                    <img src='CID:a%2Bb@example.com'>\
+                    <td background=cid:bg/1@example.com>\
+                    <div style=\"background: url(cid:bg@example.com)\">\
+                    Mention of cid:text stays, as does acid:x";
+
+        assert_eq!(
+            rewrite_cid_urls(html, base),
+            "<img src=\"/api/emails/email-1/cid/logo@example.com\">\
+             <img src='/api/emails/email-1/cid/a%2Bb@example.com'>\
+             <td background=/api/emails/email-1/cid/bg%2F1@example.com>\
+             <div style=\"background: url(/api/emails/email-1/cid/bg@example.com)\">\
+             Mention of cid:text stays, as does acid:x"
+        );
+        assert_eq!(rewrite_cid_urls("<p>plain</p>", base), "<p>plain</p>");
+    }
+
+    #[test]
+    fn test_render_document() {
+        let html = "<html><head><style>p { color: red; }</style></head>\
+                    <body onload=\"alert(1)\"><script>alert(2)</script>\
+                    <p style=\"margin: 0\" onclick=\"alert(3)\">Hello</p>\
+                    <a href=\"javascript:alert(4)\">link</a>\
+                    <img src=\"/api/emails/email-1/cid/logo@example.com\">\
+                    <img src=\"data:image/png;base64,iVBORw0KGgo=\">\
+                    <iframe src=\"https://example.com\"></iframe></body></html>";
+        let document = render_document(html);
+
+        assert!(document.starts_with("<!DOCTYPE html>"));
+        assert!(document.contains("<style>p { color: red; }</style>"));
+        assert!(document.contains("<p style=\"margin: 0\">Hello</p>"));
+        assert!(document.contains("<img src=\"/api/emails/email-1/cid/logo@example.com\">"));
+        assert!(document.contains("<img src=\"data:image/png;base64,iVBORw0KGgo=\">"));
+        for removed in [
+            "alert",
+            "javascript:",
+            "<script",
+            "<iframe",
+            "onload",
+            "onclick",
+        ] {
+            assert!(!document.contains(removed), "{} in {}", removed, document);
+        }
+    }
+
+    #[test]
+    fn test_to_text() {
+        let text = to_text(
+            "<html><head><title>Ignored</title></head><body>\
+             <h1>Hello</h1><div>World &amp; <b>more</b></div></body></html>",
+        );
+        assert_eq!(
+            text.split_whitespace().collect::<Vec<_>>(),
+            vec!["Hello", "World", "&", "more"]
+        );
+    }
+
+    #[test]
+    fn test_content_security_policy() {
+        let strict = content_security_policy(false);
+        assert!(strict.starts_with("default-src 'none';"));
+        assert!(strict.contains("img-src 'self' data:;"));
+        assert!(!strict.contains("https:"));
+
+        assert!(content_security_policy(true).contains("img-src 'self' data: http: https:;"));
+    }
+}
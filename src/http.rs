@@ -1,49 +1,522 @@
 use std::sync::Arc;
 
-use axum::extract::ws::Utf8Bytes;
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
 use axum::{
     Json, Router,
-    extract::{Path, State, WebSocketUpgrade},
+    extract::{Path, Query, State, WebSocketUpgrade},
     http::{StatusCode, Uri, header},
     response::{IntoResponse, Response},
     routing::{get, post},
 };
+use chrono::{DateTime, Utc};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use mail_parser::MessageParser;
 use mime_guess::from_path;
 use rust_embed::RustEmbed;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
 use tower_http::cors::CorsLayer;
 
-use crate::models::{AppState, Email};
+use crate::models::{
+    AppState, ClientEvent, Email, EmailAddress, EmailEvent, EmailSummary, EventEnvelope,
+    MailboxSummary, Thread, ThreadSummary,
+};
+use crate::{html, mime, thread};
 
 /// Static assets embedded in the binary
 #[derive(RustEmbed)]
 #[folder = "static/"]
 struct StaticAssets;
 
-/// API Handlers for the HTTP server
+/// Query parameters scoping a request to a single mailbox
+#[derive(Debug, Default, Deserialize)]
+pub struct MailboxQuery {
+    /// Mailbox name; all mailboxes when omitted
+    pub mailbox: Option<String>,
+}
+
+impl MailboxQuery {
+    /// Check whether an email belongs to the selected mailbox
+    fn matches(&self, email: &Email) -> bool {
+        self.mailbox
+            .as_ref()
+            .is_none_or(|mailbox| email.mailbox == *mailbox)
+    }
+}
+
+/// Query parameters filtering, sorting and paginating the email list
+#[derive(Debug, Default, Deserialize)]
+pub struct EmailQuery {
+    /// Mailbox name; all mailboxes when omitted
+    pub mailbox: Option<String>,
+    /// Only emails whose envelope sender or From header contains this text, ignoring case
+    pub from: Option<String>,
+    /// Only emails whose envelope recipients or To header contain this text, ignoring case
+    pub to: Option<String>,
+    /// Only emails whose Cc header contains this text, ignoring case
+    pub cc: Option<String>,
+    /// Only emails whose subject contains this text, ignoring case
+    pub subject: Option<String>,
+    /// Only emails whose text or HTML body contains this text, ignoring case
+    pub body: Option<String>,
+    /// Only emails with this header field, given as `Name` or as `Name:value` to also require
+    /// the value to contain `value`, ignoring case
+    pub header: Option<String>,
+    /// Only emails with (`true`) or without (`false`) attachments
+    pub has_attachment: Option<bool>,
+    /// Only emails received at or after this time (RFC 3339)
+    pub since: Option<DateTime<Utc>>,
+    /// Only emails received before this time (RFC 3339)
+    pub until: Option<DateTime<Utc>>,
+    /// Only emails where this address was a hidden (BCC) recipient
+    pub bcc: Option<String>,
+    /// Only emails with (`true`) or without (`false`) hidden recipients
+    pub has_bcc: Option<bool>,
+    /// Only delivery status notifications (`true`) or other emails (`false`)
+    pub dsn: Option<bool>,
+    /// Only DSNs reporting this action, such as `failed`, for a recipient
+    pub dsn_action: Option<String>,
+    /// Only DSNs reporting a status starting with this, such as `5` or `5.1.1`, for a recipient
+    pub dsn_status: Option<String>,
+    /// Only DSNs reporting on this final recipient
+    pub dsn_recipient: Option<String>,
+    /// Field to sort by; the order of arrival (or of relevance, for searches) when omitted
+    pub sort: Option<SortField>,
+    /// Sort direction
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Number of matching emails to skip
+    #[serde(default)]
+    pub offset: usize,
+    /// Maximum number of emails to return, capped at [`MAX_PAGE_SIZE`]; that many when omitted
+    pub limit: Option<usize>,
+}
+
+/// Largest page of emails returned by the email list and search
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Field to sort the email list by
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    /// Time of arrival
+    ReceivedAt,
+    /// Subject, ignoring case
+    Subject,
+    /// Envelope sender, ignoring case
+    From,
+    /// Message size
+    Size,
+}
+
+/// Direction to sort the email list in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// Smallest first (oldest first for dates)
+    #[default]
+    Asc,
+    /// Largest first (newest first for dates)
+    Desc,
+}
+
+impl EmailQuery {
+    /// Check whether an email passes all filters
+    fn matches(&self, email: &Email) -> bool {
+        let addresses = &email.addresses;
+        self.mailbox
+            .as_ref()
+            .is_none_or(|mailbox| email.mailbox == *mailbox)
+            && self.from.as_ref().is_none_or(|text| {
+                contains(&email.from, text) || contains_address(&addresses.from, text)
+            })
+            && self.to.as_ref().is_none_or(|text| {
+                email.to.iter().any(|rcpt| contains(rcpt, text))
+                    || contains_address(&addresses.to, text)
+            })
+            && self
+                .cc
+                .as_ref()
+                .is_none_or(|text| contains_address(&addresses.cc, text))
+            && self
+                .subject
+                .as_ref()
+                .is_none_or(|text| contains(&email.subject, text))
+            && self.body.as_ref().is_none_or(|text| {
+                [&email.text_body, &email.html_body]
+                    .into_iter()
+                    .flatten()
+                    .any(|body| contains(body, text))
+            })
+            && self.header.as_ref().is_none_or(|header| {
+                let (name, value) = match header.split_once(':') {
+                    Some((name, value)) => (name.trim(), Some(value.trim())),
+                    None => (header.trim(), None),
+                };
+                email
+                    .header_list
+                    .iter()
+                    .map(|header| (&header.name, &header.value))
+                    .chain(&email.headers)
+                    .any(|(n, v)| {
+                        n.eq_ignore_ascii_case(name) && value.is_none_or(|value| contains(v, value))
+                    })
+            })
+            && self
+                .has_attachment
+                .is_none_or(|has_attachment| email.attachments.is_empty() != has_attachment)
+            && self.since.is_none_or(|since| email.received_at >= since)
+            && self.until.is_none_or(|until| email.received_at < until)
+            && self.bcc.as_ref().is_none_or(|address| {
+                email
+                    .bcc
+                    .iter()
+                    .any(|rcpt| rcpt.eq_ignore_ascii_case(address))
+            })
+            && self
+                .has_bcc
+                .is_none_or(|has_bcc| email.bcc.is_empty() != has_bcc)
+            && self.dsn.is_none_or(|dsn| email.dsn.is_some() == dsn)
+            && self.matches_dsn_recipient(email)
+    }
+
+    /// Sort email summaries as requested and cut out the requested page
+    ///
+    /// Without a sort field, the emails keep the order they are given in.
+    fn paginate(&self, mut emails: Vec<EmailSummary>) -> Vec<EmailSummary> {
+        match self.sort {
+            None => {}
+            Some(SortField::ReceivedAt) => emails.sort_by_key(|email| email.received_at),
+            Some(SortField::Subject) => {
+                emails.sort_by_cached_key(|email| email.subject.to_lowercase())
+            }
+            Some(SortField::From) => emails.sort_by_cached_key(|email| email.from.to_lowercase()),
+            Some(SortField::Size) => emails.sort_by_key(|email| email.size),
+        }
+        if self.order == SortOrder::Desc {
+            emails.reverse();
+        }
+
+        emails
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE))
+            .collect()
+    }
+
+    /// Check whether a single DSN recipient passes all recipient filters
+    fn matches_dsn_recipient(&self, email: &Email) -> bool {
+        if self.dsn_action.is_none() && self.dsn_status.is_none() && self.dsn_recipient.is_none() {
+            return true;
+        }
+        let Some(dsn) = &email.dsn else {
+            return false;
+        };
+        dsn.recipients.iter().any(|recipient| {
+            self.dsn_action.as_ref().is_none_or(|action| {
+                recipient
+                    .action
+                    .as_ref()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(action))
+            }) && self.dsn_status.as_ref().is_none_or(|status| {
+                recipient
+                    .status
+                    .as_ref()
+                    .is_some_and(|s| s.starts_with(status.as_str()))
+            }) && self.dsn_recipient.as_ref().is_none_or(|address| {
+                recipient
+                    .final_recipient
+                    .as_ref()
+                    .is_some_and(|r| r.eq_ignore_ascii_case(address))
+            })
+        })
+    }
+}
+
+/// Emails a WebSocket client receives events for
+#[derive(Debug, Default, Deserialize)]
+pub struct Subscription {
+    /// Mailbox names; all mailboxes when empty
+    #[serde(default)]
+    pub mailboxes: Vec<String>,
+    /// Filters with the same fields as the email list query; sorting and pagination are ignored
+    #[serde(default)]
+    pub filter: EmailQuery,
+}
+
+impl Subscription {
+    /// Check whether an email is covered by the subscription
+    fn matches(&self, email: &Email) -> bool {
+        (self.mailboxes.is_empty() || self.mailboxes.contains(&email.mailbox))
+            && self.filter.matches(email)
+    }
+}
+
+impl From<MailboxQuery> for Subscription {
+    fn from(query: MailboxQuery) -> Self {
+        Self {
+            mailboxes: query.mailbox.into_iter().collect(),
+            filter: EmailQuery::default(),
+        }
+    }
+}
+
+/// Message from a WebSocket client, such as
+/// `{"type": "subscribe", "mailboxes": ["team-a"], "filter": {"to": "user@example.com"}}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SocketRequest {
+    /// Replace the subscription; answered with a snapshot of the matching emails
+    Subscribe(Subscription),
+}
+
+/// Query of the search endpoint
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    /// Words and `"quoted phrases"`, optionally prefixed with `subject:`, `from:`, `to:`,
+    /// `body:` or `attachment:`, all of which must match
+    pub q: String,
+}
+
+/// How long `/api/emails/wait` waits when no timeout is given
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest timeout accepted by `/api/emails/wait`
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Query parameters of the wait endpoint, next to the filters of [`EmailQuery`]
+///
+/// Unlike in the email list, `to` and `from` match whole addresses: a wait returns a single
+/// email, which must not be one sent to a similar address by a test running in parallel.
+#[derive(Debug, Default, Deserialize)]
+pub struct WaitQuery {
+    /// Only emails to this address, as an envelope, To or Cc recipient, ignoring case
+    pub to: Option<String>,
+    /// Only emails from this address, as the envelope sender or in the From header,
+    /// ignoring case
+    pub from: Option<String>,
+    /// How long to wait, such as `10s`, `500ms` or `10` (seconds)
+    pub timeout: Option<String>,
+}
+
+impl WaitQuery {
+    /// Check whether an email was sent from and to the requested addresses
+    fn matches(&self, email: &Email) -> bool {
+        let addresses = &email.addresses;
+        self.to.as_ref().is_none_or(|address| {
+            email
+                .to
+                .iter()
+                .chain(mailbox_addresses(&addresses.to))
+                .chain(mailbox_addresses(&addresses.cc))
+                .any(|rcpt| rcpt.eq_ignore_ascii_case(address))
+        }) && self.from.as_ref().is_none_or(|address| {
+            std::iter::once(&email.from)
+                .chain(mailbox_addresses(&addresses.from))
+                .any(|sender| sender.eq_ignore_ascii_case(address))
+        })
+    }
+
+    /// Timeout to wait for, capped at [`MAX_WAIT_TIMEOUT`], or `None` if it is malformed
+    fn timeout(&self) -> Option<Duration> {
+        let Some(timeout) = &self.timeout else {
+            return Some(DEFAULT_WAIT_TIMEOUT);
+        };
+        let timeout = match timeout.parse::<u64>() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => humantime::parse_duration(timeout).ok()?,
+        };
+        Some(timeout.min(MAX_WAIT_TIMEOUT))
+    }
+}
+
+/// Addresses of a list of mailboxes, skipping those without one
+fn mailbox_addresses(mailboxes: &[EmailAddress]) -> impl Iterator<Item = &String> {
+    mailboxes
+        .iter()
+        .filter_map(|mailbox| mailbox.address.as_ref())
+}
+
+/// Check whether `text` contains `pattern`, ignoring case
+fn contains(text: &str, pattern: &str) -> bool {
+    text.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+/// Check whether the name or address of any mailbox contains `pattern`, ignoring case
+fn contains_address(addresses: &[EmailAddress], pattern: &str) -> bool {
+    addresses.iter().any(|address| {
+        [&address.name, &address.address]
+            .into_iter()
+            .flatten()
+            .any(|text| contains(text, pattern))
+    })
+}
+
+/// Query parameters of the HTML render
+#[derive(Debug, Default, Deserialize)]
+pub struct HtmlQuery {
+    /// Allow loading remote images, which tells the sender that the email was opened
+    #[serde(default)]
+    pub remote_images: bool,
+}
+
+// API Handlers for the HTTP server
+
+/// Header carrying the number of emails matching the filters of a paginated list
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
 
 /// Get all captured emails
 ///
-/// Returns a JSON array of all emails in the system
-pub async fn get_emails(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let emails = state.emails.read().unwrap();
+/// Returns summaries of the emails matching the filters, sorted and paginated as requested.
+/// The `X-Total-Count` header holds the number of matches before pagination.
+pub async fn get_emails(
+    Query(query): Query<EmailQuery>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let summaries = state.storage.summaries(&|email| query.matches(email));
+    let total = summaries.len();
+
+    (
+        [(TOTAL_COUNT_HEADER, total.to_string())],
+        Json(query.paginate(summaries)),
+    )
+}
+
+/// Search the captured emails
+///
+/// Returns summaries of the emails matching the full-text query `q`, newest first unless sorted
+/// otherwise, narrowed down and paginated like the email list. The `X-Total-Count` header
+/// holds the number of matches before pagination.
+pub async fn search_emails(
+    Query(search): Query<SearchQuery>,
+    Query(query): Query<EmailQuery>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let summaries: Vec<EmailSummary> = state
+        .index
+        .search(&search.q)
+        .iter()
+        .filter_map(|id| state.storage.get(id))
+        .filter(|email| query.matches(email))
+        .map(|email| EmailSummary::from(&email))
+        .collect();
+    let total = summaries.len();
+
+    (
+        [(TOTAL_COUNT_HEADER, total.to_string())],
+        Json(query.paginate(summaries)),
+    )
+}
+
+/// Wait for an email
+///
+/// Returns the most recent stored email matching the same filters as the email list (except
+/// for `to` and `from`, which match whole addresses), or else the first matching email to
+/// arrive before the timeout. Responds with 204 when the timeout passes and with 400 for a
+/// malformed timeout.
+pub async fn wait_for_email(
+    Query(wait): Query<WaitQuery>,
+    Query(mut filters): Query<EmailQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Email>, StatusCode> {
+    let timeout = wait.timeout().ok_or(StatusCode::BAD_REQUEST)?;
+    let deadline = Instant::now() + timeout;
+    filters.to = None;
+    filters.from = None;
+    let matches = |email: &Email| filters.matches(email) && wait.matches(email);
+
+    // Subscribe before looking at the stored emails, so that none slips in between
+    let mut rx = state.tx.subscribe();
+    let stored = |state: &AppState| state.storage.find_last(&matches);
+    if let Some(email) = stored(&state) {
+        return Ok(Json(email));
+    }
+
+    loop {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Ok(EmailEvent::Created { email })) if matches(&email) => {
+                return Ok(Json(*email));
+            }
+            Ok(Ok(_)) => {}
+            // Some events were missed, so the email may have arrived among them
+            Ok(Err(RecvError::Lagged(_))) => {
+                if let Some(email) = stored(&state) {
+                    return Ok(Json(email));
+                }
+            }
+            Ok(Err(RecvError::Closed)) => return Err(StatusCode::SERVICE_UNAVAILABLE),
+            // Not a 408, which would have clients retry the request on their own
+            Err(_) => return Err(StatusCode::NO_CONTENT),
+        }
+    }
+}
+
+/// Get all mailboxes
+///
+/// Returns a JSON array of mailbox names with their email counts, sorted by name
+pub async fn get_mailboxes(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut counts = BTreeMap::new();
+    state.storage.visit(&mut |emails| {
+        for email in emails {
+            *counts.entry(email.mailbox.clone()).or_insert(0) += 1;
+        }
+    });
+
+    let mailboxes: Vec<MailboxSummary> = counts
+        .into_iter()
+        .map(|(name, count)| MailboxSummary { name, count })
+        .collect();
+    Json(mailboxes)
+}
+
+/// Get all conversation threads
+///
+/// Returns a JSON array of thread summaries, optionally limited to a mailbox,
+/// with the most recently active thread first
+pub async fn get_threads(
+    Query(query): Query<MailboxQuery>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut threads: Vec<ThreadSummary> = Vec::new();
+    state.storage.visit(&mut |emails| {
+        threads = thread::summarize_threads(emails.iter().filter(|email| query.matches(email)));
+    });
+    Json(threads)
+}
+
+/// Get a conversation thread by ID
+///
+/// Returns the thread with its emails, oldest first, as JSON or a 404 if not found
+pub async fn get_thread(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Thread>, StatusCode> {
+    let mut emails: Vec<Email> = state
+        .threads
+        .members(&id)
+        .iter()
+        .filter_map(|id| state.storage.get(id))
+        .collect();
+    if emails.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    Json(emails.clone())
+    emails.sort_by_key(|email| email.received_at);
+    Ok(Json(Thread { id, emails }))
 }
 
 /// Get a specific email by ID
 ///
-/// Returns a single email as JSON or a 404 if not found
+/// Returns a single email, which may be an attached message, as JSON or a 404 if not found
 pub async fn get_email(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<Email>, StatusCode> {
-    let emails = state.emails.read().unwrap();
-    let email = emails
-        .iter()
-        .find(|e| e.id == id)
-        .cloned()
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let email = find_email(&state, &id).ok_or(StatusCode::NOT_FOUND)?;
 
     Ok(Json(email))
 }
@@ -55,11 +528,7 @@ pub async fn get_attachment(
     Path((email_id, attachment_id)): Path<(String, String)>,
     State(state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let emails = state.emails.read().unwrap();
-    let email = emails
-        .iter()
-        .find(|e| e.id == email_id)
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let email = find_email(&state, &email_id).ok_or(StatusCode::NOT_FOUND)?;
 
     let attachment = email
         .attachments
@@ -75,7 +544,7 @@ pub async fn get_attachment(
         .header(header::CONTENT_TYPE, attachment.content_type.as_str())
         .header(
             header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", attachment.filename),
+            attachment_disposition(&attachment.filename),
         )
         .body(axum::body::Body::from(data.clone()))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -83,6 +552,210 @@ pub async fn get_attachment(
     Ok(response)
 }
 
+/// `Content-Disposition` value offering a download under the given filename
+///
+/// Filenames come from the sender, so control characters are dropped and quotes and
+/// backslashes replaced. Non-ASCII names are also given as an RFC 5987 `filename*`,
+/// with an ASCII approximation in `filename` for older clients.
+fn attachment_disposition(filename: &str) -> String {
+    let filename: String = filename.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if !c.is_ascii() => '_',
+            c => c,
+        })
+        .collect();
+    if filename.is_ascii() {
+        return format!("attachment; filename=\"{}\"", fallback);
+    }
+
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Split an email ID into the ID of the stored email and the path of an attached message
+///
+/// Attached messages are addressed by the ID of the stored email followed by their path,
+/// such as `<id>.2` for its second attached message and `<id>.2.1` for a message attached to that.
+fn split_email_id(id: &str) -> (&str, Option<&str>) {
+    match id.split_once('.') {
+        Some((stored_id, path)) => (stored_id, Some(path)),
+        None => (id, None),
+    }
+}
+
+/// Look up an email by ID, including attached messages
+fn find_email(state: &AppState, id: &str) -> Option<Email> {
+    let (stored_id, path) = split_email_id(id);
+    let mut email = state.storage.get(stored_id)?;
+    for segment in path.into_iter().flat_map(|path| path.split('.')) {
+        let index: usize = segment.parse().ok()?;
+        email = email
+            .attached_messages
+            .into_iter()
+            .nth(index.checked_sub(1)?)?;
+    }
+    Some(email)
+}
+
+/// Read the raw source of an email, including attached messages, mapping a missing email to a 404
+fn read_raw_email(state: &AppState, id: &str) -> Result<Vec<u8>, StatusCode> {
+    let (stored_id, path) = split_email_id(id);
+    let raw = match state.storage.raw(stored_id) {
+        Ok(Some(raw)) => raw,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to read raw email {}: {}", id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let Some(path) = path else {
+        return Ok(raw);
+    };
+
+    let message = MessageParser::default()
+        .parse(&raw)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    mime::find_attached_message(&message, path)
+        .map(|nested| nested.raw_message().to_vec())
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Get the raw source of an email
+///
+/// Returns the message exactly as received over SMTP as `message/rfc822`, or a 404 if not found
+pub async fn get_raw_email(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let raw = read_raw_email(&state, &id)?;
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "message/rfc822")
+        .header(
+            header::CONTENT_DISPOSITION,
+            attachment_disposition(&format!("{}.eml", id)),
+        )
+        .body(axum::body::Body::from(raw))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(response)
+}
+
+/// Content-Security-Policy of the MIME parts served on their own
+const PART_CONTENT_SECURITY_POLICY: &str = "sandbox; default-src 'none'";
+
+/// Get a single MIME part of an email by its path
+///
+/// Parts are addressed like `1.2.1`, matching the `path` of the nodes in the email's
+/// MIME tree. Returns the decoded content of the part, or a 404 if not found
+pub async fn get_email_part(
+    Path((id, path)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let raw = read_raw_email(&state, &id)?;
+    let message = MessageParser::default()
+        .parse(&raw)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let part = mime::find_part(&message, &path).ok_or(StatusCode::NOT_FOUND)?;
+
+    let disposition = match &part.filename {
+        Some(filename) => attachment_disposition(filename),
+        None => "inline".to_string(),
+    };
+    part_response(part, disposition)
+}
+
+/// Serve the content of a MIME part with the type declared by the sender
+///
+/// The type is not trusted: an HTML or SVG part opened directly would otherwise run its
+/// scripts on the origin of the web UI. The sandbox directive gives the document an opaque
+/// origin and blocks scripts, and nosniff keeps other types from being rendered as HTML.
+fn part_response(part: mime::PartContent, disposition: String) -> Result<Response, StatusCode> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, part.content_type)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            PART_CONTENT_SECURITY_POLICY,
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(axum::body::Body::from(part.data))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Get an inline part of an email by its Content-ID
+///
+/// Serves the targets of `cid:` URLs, which are meant for `<img>` tags in the HTML body:
+/// images other than SVG are served inline and other parts as attachments. Returns a 404 if no part has
+/// the Content-ID
+pub async fn get_inline_part(
+    Path((id, content_id)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let raw = read_raw_email(&state, &id)?;
+    let message = MessageParser::default()
+        .parse(&raw)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let part = mime::find_content_id(&message, &content_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    // Only images are displayed in place, anything else referenced by a `cid:` URL is a
+    // download. SVG documents can carry scripts, and `<img>` tags show them either way.
+    let content_type = part.content_type.to_ascii_lowercase();
+    let disposition =
+        if content_type.starts_with("image/") && !content_type.starts_with("image/svg") {
+            "inline".to_string()
+        } else {
+            match &part.filename {
+                Some(filename) => attachment_disposition(filename),
+                None => "attachment".to_string(),
+            }
+        };
+    part_response(part, disposition)
+}
+
+/// Render the sanitized HTML body of an email as a standalone document
+///
+/// `cid:` URLs are rewritten to [`get_inline_part`], so inline images are displayed
+/// as the recipient sees them. The Content-Security-Policy blocks scripts and remote
+/// loads, except remote images when requested. Returns a 404 if the email has no HTML body
+pub async fn get_email_html(
+    Path(id): Path<String>,
+    Query(query): Query<HtmlQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = find_email(&state, &id).ok_or(StatusCode::NOT_FOUND)?;
+    let html_body = email.html_body.ok_or(StatusCode::NOT_FOUND)?;
+    let html = html::rewrite_cid_urls(&html_body, &format!("/api/emails/{}/cid", id));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                html::content_security_policy(query.remote_images),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::REFERRER_POLICY, "no-referrer".to_string()),
+        ],
+        html::render_document(&html),
+    ))
+}
+
 /// Delete a specific email by ID
 ///
 /// Returns 204 No Content if successful, or 404 if the email wasn't found
@@ -90,76 +763,114 @@ pub async fn delete_email(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
 ) -> StatusCode {
-    let mut emails = state.emails.write().unwrap();
-    let initial_len = emails.len();
-    emails.retain(|e| e.id != id);
-
-    if emails.len() < initial_len {
-        StatusCode::NO_CONTENT
-    } else {
-        StatusCode::NOT_FOUND
+    match state.storage.delete(&id) {
+        Ok(true) => {
+            state.notify_deleted(vec![id]);
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            tracing::error!("Failed to delete email {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
     }
 }
 
 /// Delete all emails
 ///
-/// Returns 204 No Content after clearing all emails
-pub async fn delete_all_emails(State(state): State<Arc<AppState>>) -> StatusCode {
-    let mut emails = state.emails.write().unwrap();
-    emails.clear();
-    StatusCode::NO_CONTENT
+/// Returns 204 No Content after clearing all emails, or only those in the selected mailbox
+pub async fn delete_all_emails(
+    Query(query): Query<MailboxQuery>,
+    State(state): State<Arc<AppState>>,
+) -> StatusCode {
+    match state.storage.delete_where(&|email| query.matches(email)) {
+        Ok(deleted) => {
+            state.notify_cleared(query.mailbox, deleted);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete emails: {}", e);
+            // The mailbox was only partly cleared, so broadcast the emails that are gone
+            state.notify_deleted(e.deleted);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
 }
 
 /// WebSocket handler for real-time updates
 ///
-/// Upgrades the connection to a WebSocket and sends email updates in real-time
+/// Upgrades the connection to a WebSocket and sends email updates in real-time,
+/// initially subscribed to a single mailbox when one is given
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    Query(query): Query<MailboxQuery>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    ws.on_upgrade(|socket| handle_socket(socket, state, Subscription::from(query)))
 }
 
 /// Handle WebSocket connection for real-time email updates
 ///
-/// Sends all existing emails to the client and then streams new emails as they arrive
-async fn handle_socket(socket: axum::extract::ws::WebSocket, state: Arc<AppState>) {
-    // WebSocket implementation for real-time updates
-    let (mut sender, _receiver) = socket.split();
+/// Sends a snapshot of the emails matching the subscription and then streams changes as
+/// they happen, as versioned [`EventEnvelope`]s. The client may replace its subscription at
+/// any time with a [`SocketRequest`].
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, mut subscription: Subscription) {
+    let (mut sender, mut receiver) = socket.split();
     let mut rx = state.tx.subscribe();
 
-    // Send initial emails - clone to avoid Send issues with RwLockReadGuard
-    let emails_clone = {
-        let emails_guard = state.emails.read().unwrap();
-        emails_guard.clone()
-    };
+    if let Err(e) = send_event(&mut sender, snapshot(&state, &subscription)).await {
+        tracing::warn!("Failed to send WebSocket message: {}", e);
+        return;
+    }
 
-    for email in emails_clone.iter() {
-        if let Ok(json) = serde_json::to_string(email) {
-            if let Err(e) = sender
-                .send(axum::extract::ws::Message::Text(Utf8Bytes::from(json)))
-                .await
-            {
-                tracing::warn!("Failed to send WebSocket message: {}", e);
-                return;
-            }
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(EmailEvent::Created { email }) if !subscription.matches(&email) => continue,
+                Ok(event) => ClientEvent::from(&event),
+                // Events were missed, so start over from the stored emails
+                Err(RecvError::Lagged(_)) => snapshot(&state, &subscription),
+                Err(RecvError::Closed) => break,
+            },
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(SocketRequest::Subscribe(new_subscription)) => {
+                        subscription = new_subscription;
+                        snapshot(&state, &subscription)
+                    }
+                    Err(e) => ClientEvent::Error {
+                        message: e.to_string(),
+                    },
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        if let Err(e) = send_event(&mut sender, event).await {
+            tracing::warn!("Failed to send WebSocket message: {}", e);
+            break;
         }
     }
+}
 
-    // Listen for new emails
-    tokio::spawn(async move {
-        while let Ok(email) = rx.recv().await {
-            if let Ok(json) = serde_json::to_string(&email) {
-                if let Err(e) = sender
-                    .send(axum::extract::ws::Message::Text(Utf8Bytes::from(json)))
-                    .await
-                {
-                    tracing::warn!("Failed to send WebSocket message: {}", e);
-                    break;
-                }
-            }
-        }
-    });
+/// Summaries of the stored emails matching a subscription
+fn snapshot(state: &AppState, subscription: &Subscription) -> ClientEvent {
+    ClientEvent::Snapshot {
+        emails: state
+            .storage
+            .summaries(&|email| subscription.matches(email)),
+    }
+}
+
+/// Send an event to a WebSocket client in its versioned envelope
+async fn send_event(
+    sender: &mut SplitSink<WebSocket, Message>,
+    event: ClientEvent,
+) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(&EventEnvelope::from(event)).map_err(axum::Error::new)?;
+    sender.send(Message::Text(Utf8Bytes::from(json))).await
 }
 
 /// Serve the main index.html page
@@ -221,12 +932,21 @@ pub async fn start_http_server(
         .route("/", get(index))
         .route("/api/emails", get(get_emails))
         .route("/api/emails", post(delete_all_emails))
+        .route("/api/emails/wait", get(wait_for_email))
         .route("/api/emails/{id}", get(get_email))
         .route("/api/emails/{id}", post(delete_email))
+        .route("/api/emails/{id}/raw", get(get_raw_email))
+        .route("/api/emails/{id}/parts/{path}", get(get_email_part))
+        .route("/api/emails/{id}/cid/{content_id}", get(get_inline_part))
+        .route("/api/emails/{id}/html", get(get_email_html))
         .route(
             "/api/emails/{email_id}/attachments/{attachment_id}",
             get(get_attachment),
         )
+        .route("/api/mailboxes", get(get_mailboxes))
+        .route("/api/search", get(search_emails))
+        .route("/api/threads", get(get_threads))
+        .route("/api/threads/{id}", get(get_thread))
         .route("/ws", get(ws_handler))
         // Serve static files from embedded assets
         .route("/static/{*path}", get(static_handler))
@@ -245,67 +965,110 @@ pub async fn start_http_server(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{
+        Attachment, Dsn, DsnRecipient, Header, MessageAddresses, PROTOCOL_VERSION,
+    };
+    use crate::storage::{MemoryStorage, Storage};
     use axum::{
         body::{self, Body},
         http::{Request, StatusCode},
     };
-    use std::collections::HashMap;
-    use std::sync::{Arc, RwLock};
-    use tokio::sync::broadcast;
+    use std::sync::Arc;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
     use tower::util::ServiceExt;
-    use crate::models::Attachment;
 
     // Helper function to create a test AppState with sample emails
     fn create_test_state() -> Arc<AppState> {
-        let (tx, _) = broadcast::channel(100);
         let emails = vec![
             Email {
                 id: "test-email-1".to_string(),
                 received_at: chrono::Utc::now(),
                 from: "sender1@example.com".to_string(),
                 to: vec!["recipient1@example.com".to_string()],
+                addresses: MessageAddresses {
+                    cc: vec![EmailAddress {
+                        name: Some("Carol".to_string()),
+                        address: Some("carol@example.com".to_string()),
+                        group: None,
+                    }],
+                    ..Default::default()
+                },
                 subject: "Test Email 1".to_string(),
                 text_body: Some("This is test email 1".to_string()),
-                html_body: None,
-                headers: HashMap::new(),
-                attachments: Vec::new(),
+                header_list: vec![Header {
+                    name: "X-Campaign".to_string(),
+                    raw: " spring-sale".to_string(),
+                    value: "spring-sale".to_string(),
+                }],
+                message_id: Some("1@example.com".to_string()),
+                ..Default::default()
             },
             Email {
                 id: "test-email-2".to_string(),
                 received_at: chrono::Utc::now(),
                 from: "sender2@example.com".to_string(),
                 to: vec!["recipient2@example.com".to_string()],
+                bcc: vec!["recipient2@example.com".to_string()],
                 subject: "Test Email 2".to_string(),
                 text_body: Some("This is test email 2".to_string()),
-                html_body: None,
-                headers: HashMap::new(),
-                attachments: vec![
-                    Attachment {
-                        id: "test-attachment-1".to_string(),
-                        filename: "test.txt".to_string(),
-                        content_type: "text/plain".to_string(),
-                        size: 4,
-                        data: Some(vec![116, 101, 115, 116]), // "test" in bytes
-                    },
-                ],
+                message_id: Some("2@example.com".to_string()),
+                in_reply_to: vec!["1@example.com".to_string()],
+                references: vec!["1@example.com".to_string()],
+                thread_id: "test-email-1".to_string(),
+                attachments: vec![Attachment {
+                    id: "test-attachment-1".to_string(),
+                    filename: "test.txt".to_string(),
+                    content_type: "text/plain".to_string(),
+                    size: 4,
+                    data: Some(vec![116, 101, 115, 116]), // "test" in bytes
+                }],
+                dsn: Some(Dsn {
+                    recipients: vec![DsnRecipient {
+                        final_recipient: Some("missing@example.com".to_string()),
+                        action: Some("failed".to_string()),
+                        status: Some("5.1.1".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                mailbox: "team-a".to_string(),
+                ..Default::default()
             },
         ];
 
-        Arc::new(AppState {
-            emails: RwLock::new(emails),
-            tx,
-        })
+        let storage = MemoryStorage::default();
+        for email in emails {
+            let raw = format!("Subject: {}\r\n\r\nbody\r\n", email.subject);
+            storage.insert(email, raw.as_bytes()).unwrap();
+        }
+        Arc::new(AppState::new(Box::new(storage)))
     }
 
     // Helper function to create a router with test state
     fn create_test_router() -> Router {
-        let state = create_test_state();
+        create_test_router_with_state(create_test_state())
+    }
+
+    fn create_test_router_with_state(state: Arc<AppState>) -> Router {
         Router::new()
             .route("/api/emails", get(get_emails))
             .route("/api/emails", post(delete_all_emails))
+            .route("/api/emails/wait", get(wait_for_email))
             .route("/api/emails/{id}", get(get_email))
             .route("/api/emails/{id}", post(delete_email))
-            .route("/api/emails/{email_id}/attachments/{attachment_id}", get(get_attachment))
+            .route("/api/emails/{id}/raw", get(get_raw_email))
+            .route("/api/emails/{id}/parts/{path}", get(get_email_part))
+            .route("/api/emails/{id}/cid/{content_id}", get(get_inline_part))
+            .route("/api/emails/{id}/html", get(get_email_html))
+            .route(
+                "/api/emails/{email_id}/attachments/{attachment_id}",
+                get(get_attachment),
+            )
+            .route("/api/mailboxes", get(get_mailboxes))
+            .route("/api/search", get(search_emails))
+            .route("/api/threads", get(get_threads))
+            .route("/api/threads/{id}", get(get_thread))
             .route("/ws", get(ws_handler))
             .route("/", get(index))
             .route("/static/{path}", get(static_handler))
@@ -331,21 +1094,29 @@ mod tests {
         let body = body::to_bytes(response.into_body(), 1024 * 1024)
             .await
             .unwrap();
-        let emails: Vec<Email> = serde_json::from_slice(&body).unwrap();
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
 
         assert_eq!(emails.len(), 2);
         assert_eq!(emails[0].id, "test-email-1");
         assert_eq!(emails[1].id, "test-email-2");
+
+        // Summaries only, without bodies
+        assert!(!String::from_utf8_lossy(&body).contains("text_body"));
+        assert_eq!(emails[1].snippet, "This is test email 2");
+        assert_eq!(emails[1].attachment_count, 1);
+        assert_eq!(emails[1].thread_id, "test-email-1");
+        assert!(emails[1].flags.bcc && emails[1].flags.dsn);
+        assert!(!emails[0].flags.bcc && !emails[0].flags.dsn);
     }
 
     #[tokio::test]
-    async fn test_get_email() {
+    async fn test_get_emails_by_mailbox() {
         let app = create_test_router();
 
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/api/emails/test-email-1")
+                    .uri("/api/emails?mailbox=team-a")
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -357,71 +1128,630 @@ mod tests {
         let body = body::to_bytes(response.into_body(), 1024 * 1024)
             .await
             .unwrap();
-        let email: Email = serde_json::from_slice(&body).unwrap();
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
 
-        assert_eq!(email.id, "test-email-1");
-        assert_eq!(email.from, "sender1@example.com");
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].id, "test-email-2");
     }
 
     #[tokio::test]
-    async fn test_get_email_not_found() {
+    async fn test_get_emails_by_bcc() {
         let app = create_test_router();
 
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/emails/nonexistent")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+        for (query, expected) in [
+            ("bcc=Recipient2@example.com", vec!["test-email-2"]),
+            ("bcc=recipient1@example.com", vec![]),
+            ("has_bcc=true", vec!["test-email-2"]),
+            ("has_bcc=false", vec!["test-email-1"]),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/api/emails?{}", query))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let body = body::to_bytes(response.into_body(), 1024 * 1024)
+                .await
+                .unwrap();
+            let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<&str> = emails.iter().map(|e| e.id.as_str()).collect();
+            assert_eq!(ids, expected, "query: {}", query);
+        }
     }
 
     #[tokio::test]
-    async fn test_delete_email() {
+    async fn test_get_emails_by_dsn() {
+        let app = create_test_router();
+
+        for (query, expected) in [
+            ("dsn=true", vec!["test-email-2"]),
+            ("dsn=false", vec!["test-email-1"]),
+            ("dsn_action=failed", vec!["test-email-2"]),
+            ("dsn_action=delayed", vec![]),
+            ("dsn_status=5", vec!["test-email-2"]),
+            ("dsn_status=4", vec![]),
+            (
+                "dsn_recipient=Missing@example.com&dsn_status=5.1.1",
+                vec!["test-email-2"],
+            ),
+            ("dsn_recipient=other@example.com&dsn_action=failed", vec![]),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/api/emails?{}", query))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = body::to_bytes(response.into_body(), 1024 * 1024)
+                .await
+                .unwrap();
+            let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<&str> = emails.iter().map(|e| e.id.as_str()).collect();
+            assert_eq!(ids, expected, "query: {}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_emails_search() {
         let app = create_test_router();
 
+        for (query, expected) in [
+            ("from=SENDER1", vec!["test-email-1"]),
+            ("to=recipient2@example.com", vec!["test-email-2"]),
+            ("cc=carol", vec!["test-email-1"]),
+            ("subject=email%202", vec!["test-email-2"]),
+            ("body=TEST%20EMAIL", vec!["test-email-1", "test-email-2"]),
+            ("header=x-campaign", vec!["test-email-1"]),
+            ("header=X-Campaign:SPRING", vec!["test-email-1"]),
+            ("header=X-Campaign:winter", vec![]),
+            ("has_attachment=true", vec!["test-email-2"]),
+            ("has_attachment=false", vec!["test-email-1"]),
+            (
+                "since=2000-01-01T00:00:00Z",
+                vec!["test-email-1", "test-email-2"],
+            ),
+            ("until=2000-01-01T00:00:00Z", vec![]),
+            (
+                "sort=subject&order=desc",
+                vec!["test-email-2", "test-email-1"],
+            ),
+            ("sort=subject&limit=1", vec!["test-email-1"]),
+            ("sort=subject&offset=1&limit=1", vec!["test-email-2"]),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/api/emails?{}", query))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "query: {}", query);
+
+            // The total counts all matches, before pagination
+            let total = if query.contains("limit") {
+                2
+            } else {
+                expected.len()
+            };
+            assert_eq!(
+                response.headers()[TOTAL_COUNT_HEADER],
+                total.to_string().as_str(),
+                "query: {}",
+                query
+            );
+
+            let body = body::to_bytes(response.into_body(), 1024 * 1024)
+                .await
+                .unwrap();
+            let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<&str> = emails.iter().map(|e| e.id.as_str()).collect();
+            assert_eq!(ids, expected, "query: {}", query);
+        }
+
         let response = app
-            .clone()
             .oneshot(
                 Request::builder()
-                    .method("POST")
-                    .uri("/api/emails/test-email-1")
+                    .uri("/api/emails?sort=color")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 
-        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    #[test]
+    fn test_paginate_caps_limit() {
+        let summaries = vec![EmailSummary::from(&Email::default()); MAX_PAGE_SIZE + 1];
+        for query in [
+            EmailQuery::default(),
+            EmailQuery {
+                limit: Some(usize::MAX),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(query.paginate(summaries.clone()).len(), MAX_PAGE_SIZE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_emails_newest_first() {
+        let now = chrono::Utc::now();
+        let email = |id: &str, minutes_ago: i64| Email {
+            id: id.to_string(),
+            received_at: now - chrono::Duration::minutes(minutes_ago),
+            subject: "Weekly report".to_string(),
+            ..Default::default()
+        };
+        let storage = MemoryStorage::new(vec![email("old", 2), email("new", 1)]);
+        let app = create_test_router_with_state(Arc::new(AppState::new(Box::new(storage))));
 
-        // Verify the email was deleted
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/api/emails")
+                    .uri("/api/search?q=report")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-
+        assert_eq!(response.status(), StatusCode::OK);
         let body = body::to_bytes(response.into_body(), 1024 * 1024)
             .await
             .unwrap();
-        let emails: Vec<Email> = serde_json::from_slice(&body).unwrap();
-
-        assert_eq!(emails.len(), 1);
-        assert_eq!(emails[0].id, "test-email-2");
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<&str> = emails.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["new", "old"]);
     }
 
     #[tokio::test]
-    async fn test_delete_nonexistent_email() {
-        let app = create_test_router();
-
+    async fn test_search_emails() {
+        let state = create_test_state();
+        let app = create_test_router_with_state(state.clone());
+
+        for (query, expected) in [
+            (
+                "q=test%20email&sort=received_at",
+                vec!["test-email-1", "test-email-2"],
+            ),
+            ("q=%22email%202%22", vec!["test-email-2"]),
+            ("q=subject:1", vec!["test-email-1"]),
+            ("q=from:sender2@example.com", vec!["test-email-2"]),
+            ("q=attachment:test", vec!["test-email-2"]),
+            ("q=test&mailbox=team-a", vec!["test-email-2"]),
+            (
+                "q=test&sort=subject&order=desc&limit=1",
+                vec!["test-email-2"],
+            ),
+            ("q=missing", vec![]),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/api/search?{}", query))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "query: {}", query);
+
+            let body = body::to_bytes(response.into_body(), 1024 * 1024)
+                .await
+                .unwrap();
+            let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<&str> = emails.iter().map(|e| e.id.as_str()).collect();
+            assert_eq!(ids, expected, "query: {}", query);
+        }
+
+        // Deleted emails leave the index
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/emails/test-email-2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.index.search("attachment:test").is_empty());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/search")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Read the next event from a WebSocket test client
+    async fn next_event(
+        socket: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    ) -> EventEnvelope {
+        loop {
+            if let WsMessage::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// IDs of the emails in a snapshot event
+    fn snapshot_ids(envelope: EventEnvelope) -> Vec<String> {
+        match envelope.event {
+            ClientEvent::Snapshot { emails } => emails.into_iter().map(|email| email.id).collect(),
+            event => panic!("expected a snapshot, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_protocol() {
+        let state = create_test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = create_test_router_with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/ws?mailbox=default", addr))
+                .await
+                .unwrap();
+
+        let envelope = next_event(&mut socket).await;
+        assert_eq!(envelope.version, PROTOCOL_VERSION);
+        assert_eq!(snapshot_ids(envelope), vec!["test-email-1"]);
+
+        // Subscribing replaces the mailbox and filters, and answers with a new snapshot
+        socket
+            .send(WsMessage::text(
+                r#"{"type": "subscribe", "mailboxes": ["team-a"], "filter": {"has_attachment": true}}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            snapshot_ids(next_event(&mut socket).await),
+            vec!["test-email-2"]
+        );
+
+        // Only new emails matching the subscription are sent
+        for (id, template) in [("other", "test-email-1"), ("matching", "test-email-2")] {
+            let mut email = state.storage.get(template).unwrap();
+            email.id = id.to_string();
+            state
+                .tx
+                .send(EmailEvent::Created {
+                    email: Box::new(email),
+                })
+                .unwrap();
+        }
+        match next_event(&mut socket).await.event {
+            ClientEvent::Created { email } => assert_eq!(email.id, "matching"),
+            event => panic!("expected a created event, got {:?}", event),
+        }
+
+        socket
+            .send(WsMessage::text(r#"{"type": "unsubscribe"}"#))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut socket).await.event,
+            ClientEvent::Error { .. }
+        ));
+
+        // Clearing a mailbox reaches every client as a single event
+        let response = create_test_router_with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/emails?mailbox=team-a")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            next_event(&mut socket).await.event,
+            ClientEvent::Cleared {
+                mailbox: Some("team-a".to_string()),
+                ids: vec!["test-email-2".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_email() {
+        let state = create_test_state();
+        let app = create_test_router_with_state(state.clone());
+
+        // Already stored
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/wait?to=RECIPIENT1@example.com&cc=carol&subject=email%201")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let email: Email = serde_json::from_slice(&body).unwrap();
+        assert_eq!(email.id, "test-email-1");
+
+        // Arriving while waiting
+        let waiting = tokio::spawn(
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/api/emails/wait?from=late@example.com&timeout=5s")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut late = state.storage.get("test-email-1").unwrap();
+        late.id = "test-email-3".to_string();
+        late.from = "late@example.com".to_string();
+        state
+            .tx
+            .send(EmailEvent::Created {
+                email: Box::new(late),
+            })
+            .unwrap();
+        let response = waiting.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let email: Email = serde_json::from_slice(&body).unwrap();
+        assert_eq!(email.id, "test-email-3");
+
+        for (uri, status) in [
+            (
+                "/api/emails/wait?to=nobody@example.com&timeout=50ms",
+                StatusCode::NO_CONTENT,
+            ),
+            // Part of an address is not enough
+            (
+                "/api/emails/wait?to=recipient1@example&timeout=50ms",
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                "/api/emails/wait?from=sender1@example&timeout=50ms",
+                StatusCode::NO_CONTENT,
+            ),
+            ("/api/emails/wait?timeout=soon", StatusCode::BAD_REQUEST),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_mailboxes() {
+        let app = create_test_router();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/mailboxes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let mailboxes: Vec<MailboxSummary> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            mailboxes,
+            vec![
+                MailboxSummary {
+                    name: "default".to_string(),
+                    count: 1,
+                },
+                MailboxSummary {
+                    name: "team-a".to_string(),
+                    count: 1,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_threads() {
+        let app = create_test_router();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/threads")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let threads: Vec<ThreadSummary> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].id, "test-email-1");
+        assert_eq!(threads[0].subject, "Test Email 1");
+        assert_eq!(threads[0].email_ids, vec!["test-email-1", "test-email-2"]);
+
+        // Scoped to a mailbox
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/threads?mailbox=team-a")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let threads: Vec<ThreadSummary> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].email_ids, vec!["test-email-2"]);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/threads/test-email-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let thread: Thread = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<&str> = thread
+            .emails
+            .iter()
+            .map(|email| email.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["test-email-1", "test-email-2"]);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/threads/test-email-2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_email() {
+        let app = create_test_router();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/test-email-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let email: Email = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(email.id, "test-email-1");
+        assert_eq!(email.from, "sender1@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_get_email_not_found() {
+        let app = create_test_router();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/nonexistent")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_delete_email() {
+        let app = create_test_router();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/emails/test-email-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Verify the email was deleted
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].id, "test-email-2");
+    }
+
+    #[tokio::test]
+    async fn test_delete_nonexistent_email() {
+        let app = create_test_router();
+
         // Try to delete a non-existent email
         let response = app
             .oneshot(
@@ -470,11 +1800,49 @@ mod tests {
         let body = body::to_bytes(response.into_body(), 1024 * 1024)
             .await
             .unwrap();
-        let emails: Vec<Email> = serde_json::from_slice(&body).unwrap();
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
 
         assert_eq!(emails.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_delete_all_emails_in_mailbox() {
+        let app = create_test_router();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/emails?mailbox=team-a")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Verify only the team-a mailbox was cleared
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let emails: Vec<EmailSummary> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].id, "test-email-1");
+    }
+
     #[tokio::test]
     async fn test_get_attachment() {
         let app = create_test_router();
@@ -506,6 +1874,386 @@ mod tests {
         assert_eq!(body, vec![116, 101, 115, 116]); // "test" in bytes
     }
 
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\""
+        );
+        assert_eq!(
+            attachment_disposition("a\"b\r\nX-Injected: 1\\.txt"),
+            "attachment; filename=\"a_bX-Injected: 1_.txt\""
+        );
+        assert_eq!(
+            attachment_disposition("résumé 1.pdf"),
+            "attachment; filename=\"r_sum_ 1.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.pdf"
+        );
+        assert!(header::HeaderValue::from_str(&attachment_disposition("\u{7f}ü\"")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_raw_email() {
+        let app = create_test_router();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/test-email-1/raw")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "message/rfc822");
+        assert_eq!(
+            response.headers()["content-disposition"],
+            "attachment; filename=\"test-email-1.eml\""
+        );
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, "Subject: Test Email 1\r\n\r\nbody\r\n");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/nonexistent/raw")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_email_part() {
+        let app = create_test_router();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/test-email-1/parts/1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers()["content-disposition"], "inline");
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, "body\r\n");
+
+        for uri in [
+            "/api/emails/test-email-1/parts/1.1",
+            "/api/emails/nonexistent/parts/1",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_email_part_is_sandboxed() {
+        let state = create_test_state();
+        let mut email = state.storage.get("test-email-1").unwrap();
+        email.id = "html-email".to_string();
+        let raw = "Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   Hello\r\n\
+                   --b\r\n\
+                   Content-Type: text/html\r\n\
+                   \r\n\
+                   <script>alert(document.cookie)</script>\r\n\
+                   --b--\r\n";
+        state.storage.insert(email, raw.as_bytes()).unwrap();
+        let app = create_test_router_with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/html-email/parts/1.2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            response.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html")
+        );
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "sandbox; default-src 'none'"
+        );
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    }
+
+    #[tokio::test]
+    async fn test_get_email_html_with_inline_image() {
+        let state = create_test_state();
+        let mut email = state.storage.get("test-email-1").unwrap();
+        email.id = "html-email".to_string();
+        email.html_body = Some("<img src=\"cid:logo@example.com\">".to_string());
+        let raw = "Content-Type: multipart/related; boundary=\"b\"\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/html\r\n\
+                   \r\n\
+                   <img src=\"cid:logo@example.com\">\r\n\
+                   --b\r\n\
+                   Content-Type: image/png\r\n\
+                   Content-ID: <logo@example.com>\r\n\
+                   Content-Transfer-Encoding: base64\r\n\
+                   \r\n\
+                   iVBORw0KGgo=\r\n\
+                   --b\r\n\
+                   Content-Type: image/svg+xml\r\n\
+                   Content-ID: <page@example.com>\r\n\
+                   \r\n\
+                   <svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>\r\n\
+                   --b--\r\n";
+        state.storage.insert(email, raw.as_bytes()).unwrap();
+        let app = create_test_router_with_state(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/html-email/html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<img src=\"/api/emails/html-email/cid/logo@example.com\">"));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/html-email/cid/logo@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert_eq!(response.headers()["content-disposition"], "inline");
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "sandbox; default-src 'none'"
+        );
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, b"\x89PNG\r\n\x1a\n"[..]);
+
+        // Anything but a plain image is a download
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/html-email/cid/page@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-disposition"], "attachment");
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "sandbox; default-src 'none'"
+        );
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+
+        // No HTML body, and no part with the Content-ID
+        for uri in [
+            "/api/emails/test-email-1/html",
+            "/api/emails/html-email/cid/missing@example.com",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_email_html_is_sanitized() {
+        let state = create_test_state();
+        let mut email = state.storage.get("test-email-1").unwrap();
+        email.id = "script-email".to_string();
+        email.html_body = Some(
+            "<p onclick=\"alert(1)\">Hi</p><script>alert(2)</script>\
+             <img src=\"https://tracker.example.com/open.gif\">"
+                .to_string(),
+        );
+        state
+            .storage
+            .insert(email, b"Subject: Script\r\n\r\n")
+            .unwrap();
+        let app = create_test_router_with_state(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/script-email/html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let csp = response.headers()["content-security-policy"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(csp.starts_with("default-src 'none';"));
+        assert!(!csp.contains("https:"));
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<p>Hi</p>"));
+        assert!(!body.contains("alert"));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/script-email/html?remote_images=true")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let csp = response.headers()["content-security-policy"]
+            .to_str()
+            .unwrap();
+        assert!(csp.contains("img-src 'self' data: http: https:;"));
+    }
+
+    #[tokio::test]
+    async fn test_get_attached_message() {
+        let state = create_test_state();
+        let mut child = state.storage.get("test-email-2").unwrap();
+        child.id = "fwd-email.1".to_string();
+        child.parent_id = Some("fwd-email".to_string());
+        let mut email = state.storage.get("test-email-1").unwrap();
+        email.id = "fwd-email".to_string();
+        email.attached_messages.push(child);
+        let raw = "Subject: Fwd\r\n\
+                   Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: message/rfc822\r\n\
+                   \r\n\
+                   Subject: Test Email 2\r\n\
+                   \r\n\
+                   forwarded\r\n\
+                   --b--\r\n";
+        state.storage.insert(email, raw.as_bytes()).unwrap();
+        let app = create_test_router_with_state(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/fwd-email.1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        let child: Email = serde_json::from_slice(&body).unwrap();
+        assert_eq!(child.subject, "Test Email 2");
+        assert_eq!(child.parent_id.as_deref(), Some("fwd-email"));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/fwd-email.1/attachments/test-attachment-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/emails/fwd-email.1/raw")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024 * 1024)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"Subject: Test Email 2\r\n"));
+
+        for uri in [
+            "/api/emails/fwd-email.2",
+            "/api/emails/fwd-email.x",
+            "/api/emails/fwd-email.2/raw",
+            "/api/emails/test-email-1.1",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
     #[tokio::test]
     async fn test_get_attachment_not_found() {
         let app = create_test_router();
@@ -543,12 +2291,7 @@ mod tests {
         let app = create_test_router();
 
         let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
             .await
             .unwrap();
 
@@ -3,20 +3,45 @@
 //! This is the main entry point for the MailHits application.
 //! It starts both the SMTP server for capturing emails and the HTTP server for the web interface.
 
+pub mod auth;
+pub mod calendar;
+pub mod dsn;
+pub mod html;
 pub mod http;
+pub mod mime;
 pub mod models;
+pub mod retention;
+pub mod search;
 pub mod smtp;
+pub mod storage;
+pub mod thread;
+pub mod tls;
 
-use clap::Parser;
-use std::sync::{Arc, RwLock};
-use tokio::sync::broadcast;
-use tracing_subscriber;
+use clap::{ArgGroup, Parser, ValueEnum};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::auth::AuthMode;
 use crate::models::AppState;
+use crate::retention::RetentionPolicy;
+use crate::smtp::{MailboxRule, SmtpConfig};
+use crate::storage::{DirectoryStorage, MemoryStorage, Storage};
+
+/// Where captured emails are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StorageBackend {
+    /// Keep emails in memory; they are lost on restart
+    Memory,
+    /// Persist emails to the data directory
+    Directory,
+}
 
 /// Command line arguments for the application
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("tls_listener").args(["starttls", "smtps_port"]).multiple(true)))]
 struct Args {
     /// SMTP server port
     #[arg(short, long, default_value_t = 1025)]
@@ -25,15 +50,117 @@ struct Args {
     /// HTTP server port
     #[arg(short = 'p', long, default_value_t = 3000)]
     http_port: u16,
+
+    /// Maximum accepted message size in bytes, advertised via the SMTP SIZE extension
+    #[arg(long, default_value_t = smtp::DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    /// Implicit TLS (SMTPS) server port, disabled unless set
+    #[arg(long)]
+    smtps_port: Option<u16>,
+
+    /// Offer STARTTLS on the SMTP server (uses a self-signed certificate unless one is given)
+    #[arg(long)]
+    starttls: bool,
+
+    /// PEM encoded TLS certificate chain, for `--starttls` or `--smtps-port`
+    #[arg(long, requires = "tls_key", requires = "tls_listener")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM encoded TLS private key
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Only accept SMTP AUTH for this `username:password` pair (repeatable);
+    /// any credentials are accepted when none are given
+    #[arg(long = "auth-user", value_name = "USER:PASSWORD")]
+    auth_users: Vec<String>,
+
+    /// Deliver mail for a recipient domain to a named mailbox (repeatable);
+    /// mail from authenticated clients goes to the mailbox named after the AUTH user
+    #[arg(long = "mailbox-rule", value_name = "DOMAIN=MAILBOX")]
+    mailbox_rules: Vec<String>,
+
+    /// Storage backend for captured emails
+    #[arg(long, value_enum, default_value_t = StorageBackend::Memory)]
+    storage: StorageBackend,
+
+    /// Directory used by the `directory` storage backend
+    #[arg(long, default_value = "mailhits-data")]
+    data_dir: PathBuf,
+
+    /// Keep at most this many of the newest emails
+    #[arg(long, value_name = "COUNT")]
+    max_messages: Option<usize>,
+
+    /// Delete emails older than this, e.g. `30m`, `12h` or `7d`
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_age: Option<Duration>,
+
+    /// Keep at most this many stored bytes (raw messages and decoded attachments),
+    /// deleting the oldest emails first
+    #[arg(long, value_name = "BYTES")]
+    max_total_size: Option<usize>,
 }
 
-/// Creates the application state
-fn create_app_state() -> Arc<AppState> {
-    let (tx, _) = broadcast::channel(100);
-    Arc::new(AppState {
-        emails: RwLock::new(Vec::new()),
-        tx,
-    })
+/// Creates the SMTP listener configuration from the command line arguments
+fn create_smtp_config(args: &Args) -> io::Result<Arc<SmtpConfig>> {
+    let starttls = args.starttls;
+    let tls = if starttls || args.smtps_port.is_some() {
+        Some(tls::build_tls_acceptor(
+            args.tls_cert.as_deref(),
+            args.tls_key.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
+    let auth = if args.auth_users.is_empty() {
+        AuthMode::AcceptAny
+    } else {
+        AuthMode::from_user_list(&args.auth_users).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--auth-user entries must have the form username:password",
+            )
+        })?
+    };
+
+    let mailbox_rules = args
+        .mailbox_rules
+        .iter()
+        .map(|rule| MailboxRule::parse(rule))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--mailbox-rule entries must have the form domain=mailbox",
+            )
+        })?;
+
+    Ok(Arc::new(SmtpConfig {
+        max_message_size: args.max_message_size,
+        tls,
+        starttls,
+        smtps_port: args.smtps_port,
+        auth,
+        mailbox_rules,
+    }))
+}
+
+/// Creates the application state with the selected storage backend
+fn create_app_state(args: &Args) -> io::Result<Arc<AppState>> {
+    let storage: Box<dyn Storage> = match args.storage {
+        StorageBackend::Memory => Box::new(MemoryStorage::default()),
+        StorageBackend::Directory => Box::new(DirectoryStorage::open(&args.data_dir)?),
+    };
+    let mut state = AppState::new(storage);
+    state.retention = RetentionPolicy {
+        max_count: args.max_messages,
+        max_age: args.max_age,
+        max_bytes: args.max_total_size,
+    };
+    Ok(Arc::new(state))
 }
 
 /// Main entry point for the application
@@ -43,19 +170,28 @@ fn create_app_state() -> Arc<AppState> {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize tracing
-    tracing_subscriber::fmt().with_max_level(tracing::Level::INFO).init();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
 
     // Parse command-line arguments
     let args = Args::parse();
 
     // Create application state
-    let state = create_app_state();
+    let state = create_app_state(&args)?;
+
+    // Apply the retention limits to previously stored emails, then keep pruning periodically
+    if !state.retention.is_unlimited() {
+        retention::prune(&state)?;
+        tokio::spawn(retention::run_pruner(state.clone()));
+    }
 
     // Start SMTP server in a separate task
     let smtp_state = state.clone();
     let smtp_port = args.smtp_port;
+    let smtp_config = create_smtp_config(&args)?;
     tokio::spawn(async move {
-        smtp::start_smtp_server(smtp_state, smtp_port).await;
+        smtp::start_smtp_server(smtp_state, smtp_port, smtp_config).await;
     });
 
     // Start HTTP server (this will block until the server shuts down)
@@ -73,6 +209,10 @@ mod tests {
         let args = Args::parse_from(["mailhits"]);
         assert_eq!(args.smtp_port, 1025);
         assert_eq!(args.http_port, 3000);
+        assert_eq!(args.max_message_size, smtp::DEFAULT_MAX_MESSAGE_SIZE);
+        assert!(!args.starttls);
+        assert_eq!(args.smtps_port, None);
+        assert_eq!(args.storage, StorageBackend::Memory);
     }
 
     #[test]
@@ -82,10 +222,143 @@ mod tests {
         assert_eq!(args.http_port, 4000);
     }
 
+    #[test]
+    fn test_args_tls_requires_cert_and_key() {
+        assert!(Args::try_parse_from(["mailhits", "--tls-cert", "cert.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_args_tls_certificate_requires_listener() {
+        let cert = ["--tls-cert", "cert.pem", "--tls-key", "key.pem"];
+        assert!(Args::try_parse_from(["mailhits"].iter().chain(&cert)).is_err());
+        assert!(Args::try_parse_from(["mailhits", "--starttls"].iter().chain(&cert)).is_ok());
+        assert!(
+            Args::try_parse_from(["mailhits", "--smtps-port", "1465"].iter().chain(&cert)).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_create_smtp_config_starttls() {
+        let args = Args::parse_from(["mailhits"]);
+        assert!(create_smtp_config(&args).unwrap().tls.is_none());
+
+        let args = Args::parse_from(["mailhits", "--starttls"]);
+        assert!(create_smtp_config(&args).unwrap().tls.is_some());
+    }
+
+    #[test]
+    fn test_create_smtp_config_smtps() {
+        let args = Args::parse_from(["mailhits", "--smtps-port", "1465"]);
+        let config = create_smtp_config(&args).unwrap();
+        assert_eq!(config.smtps_port, Some(1465));
+        assert!(config.tls.is_some());
+        assert!(!config.starttls);
+    }
+
+    #[test]
+    fn test_create_smtp_config_smtps_with_certificate() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("mailhits-main-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        let args = Args::parse_from([
+            "mailhits",
+            "--smtps-port",
+            "1465",
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ]);
+        let config = create_smtp_config(&args);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The certificate is used for SMTPS only; STARTTLS still needs --starttls
+        let config = config.unwrap();
+        assert!(config.tls.is_some());
+        assert!(!config.starttls);
+    }
+
+    #[test]
+    fn test_create_smtp_config_auth_users() {
+        let args = Args::parse_from(["mailhits"]);
+        let config = create_smtp_config(&args).unwrap();
+        assert!(matches!(config.auth, AuthMode::AcceptAny));
+
+        let args = Args::parse_from(["mailhits", "--auth-user", "alice:secret"]);
+        let config = create_smtp_config(&args).unwrap();
+        assert!(config.auth.check_password("alice", "secret"));
+        assert!(!config.auth.check_password("alice", "wrong"));
+
+        let args = Args::parse_from(["mailhits", "--auth-user", "alice"]);
+        assert!(create_smtp_config(&args).is_err());
+    }
+
+    #[test]
+    fn test_create_smtp_config_mailbox_rules() {
+        let args = Args::parse_from(["mailhits", "--mailbox-rule", "team-a.test=team-a"]);
+        let config = create_smtp_config(&args).unwrap();
+        assert_eq!(
+            config.mailbox_rules,
+            vec![MailboxRule {
+                domain: "team-a.test".to_string(),
+                mailbox: "team-a".to_string(),
+            }]
+        );
+
+        let args = Args::parse_from(["mailhits", "--mailbox-rule", "team-a.test"]);
+        assert!(create_smtp_config(&args).is_err());
+    }
+
     #[test]
     fn test_create_app_state() {
-        let state = create_app_state();
-        let emails = state.emails.read().unwrap();
-        assert_eq!(emails.len(), 0);
+        let args = Args::parse_from(["mailhits"]);
+        let state = create_app_state(&args).unwrap();
+        assert_eq!(state.storage.list().len(), 0);
+        assert!(state.retention.is_unlimited());
+    }
+
+    #[test]
+    fn test_create_app_state_retention() {
+        let args = Args::parse_from([
+            "mailhits",
+            "--max-messages",
+            "100",
+            "--max-age",
+            "2h",
+            "--max-total-size",
+            "1048576",
+        ]);
+        let state = create_app_state(&args).unwrap();
+        assert_eq!(
+            state.retention,
+            RetentionPolicy {
+                max_count: Some(100),
+                max_age: Some(Duration::from_secs(2 * 60 * 60)),
+                max_bytes: Some(1048576),
+            }
+        );
+
+        assert!(Args::try_parse_from(["mailhits", "--max-age", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_create_app_state_directory_storage() {
+        let dir = std::env::temp_dir().join(format!("mailhits-main-{}", uuid::Uuid::new_v4()));
+        let args = Args::parse_from([
+            "mailhits",
+            "--storage",
+            "directory",
+            "--data-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let state = create_app_state(&args).unwrap();
+        assert_eq!(state.storage.list().len(), 0);
+        assert!(dir.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
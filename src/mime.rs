@@ -0,0 +1,343 @@
+//! MIME structure of parsed messages
+//!
+//! Builds the part tree exposed in the API and resolves part paths (`1`, `1.2`, `1.2.1`, ...)
+//! back to the parts of a parsed message. Part contents are not kept in memory; they are
+//! extracted from the stored raw message on demand.
+
+use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
+
+use crate::models::MimePart;
+
+/// Decoded content of a single part, ready to be served
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartContent {
+    /// Content type to serve the content with
+    pub content_type: String,
+    /// File name of the part, if it has one
+    pub filename: Option<String>,
+    /// Decoded content; the raw body for multiparts
+    pub data: Vec<u8>,
+}
+
+/// Build the MIME tree of a parsed message
+pub fn mime_tree(message: &Message) -> MimePart {
+    build_node(message, 0, "1".to_string())
+}
+
+/// Build the node for a part of `message` and its descendants
+fn build_node(message: &Message, part_id: usize, path: String) -> MimePart {
+    let part = &message.parts[part_id];
+    let children = match &part.body {
+        PartType::Multipart(ids) => ids
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| build_node(message, id as usize, format!("{}.{}", path, i + 1)))
+            .collect(),
+        PartType::Message(nested) => vec![build_node(nested, 0, format!("{}.1", path))],
+        _ => Vec::new(),
+    };
+    let size = match &part.body {
+        PartType::Multipart(_) => raw_body(message, part).len(),
+        _ => part.contents().len(),
+    };
+
+    MimePart {
+        content_type: content_type(part),
+        charset: part
+            .content_type()
+            .and_then(|ct| ct.attribute("charset"))
+            .map(str::to_string),
+        transfer_encoding: part.content_transfer_encoding().map(str::to_string),
+        disposition: part
+            .content_disposition()
+            .map(|cd| cd.ctype().to_ascii_lowercase()),
+        filename: part.attachment_name().map(str::to_string),
+        content_id: part
+            .content_id()
+            .map(|id| id.trim_matches(['<', '>']).to_string()),
+        size,
+        children,
+        path,
+    }
+}
+
+/// Find a part by its path and extract its content
+pub fn find_part(message: &Message, path: &str) -> Option<PartContent> {
+    let mut segments = path.split('.');
+    if segments.next()? != "1" {
+        return None;
+    }
+
+    let mut message = message;
+    let mut part_id = 0;
+    for segment in segments {
+        let index: usize = segment.parse().ok()?;
+        match &message.parts.get(part_id)?.body {
+            PartType::Multipart(ids) => part_id = *ids.get(index.checked_sub(1)?)? as usize,
+            PartType::Message(nested) if index == 1 => {
+                message = nested;
+                part_id = 0;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(part_content(message, message.parts.get(part_id)?))
+}
+
+/// Find the part with the given Content-ID, without angle brackets, and extract its content
+pub fn find_content_id(message: &Message, content_id: &str) -> Option<PartContent> {
+    message
+        .parts
+        .iter()
+        .find(|part| {
+            part.content_id()
+                .is_some_and(|id| id.trim_matches(['<', '>']) == content_id)
+        })
+        .map(|part| part_content(message, part))
+}
+
+/// Extract the content of a part of `message`
+fn part_content(message: &Message, part: &MessagePart) -> PartContent {
+    let (content_type, data) = match &part.body {
+        // Text is decoded to UTF-8 by the parser
+        PartType::Text(_) | PartType::Html(_) => (
+            format!("{}; charset=utf-8", content_type(part)),
+            part.contents().to_vec(),
+        ),
+        PartType::Multipart(_) => (
+            multipart_content_type(part),
+            raw_body(message, part).to_vec(),
+        ),
+        _ => (content_type(part), part.contents().to_vec()),
+    };
+
+    PartContent {
+        content_type,
+        filename: part.attachment_name().map(str::to_string),
+        data,
+    }
+}
+
+/// MIME type of a part without parameters, defaulting by the kind of body
+fn content_type(part: &MessagePart) -> String {
+    match part.content_type() {
+        Some(ct) => match ct.subtype() {
+            Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+            None => ct.ctype().to_string(),
+        }
+        .to_ascii_lowercase(),
+        None => match part.body {
+            PartType::Text(_) => "text/plain",
+            PartType::Html(_) => "text/html",
+            PartType::Message(_) => "message/rfc822",
+            PartType::Multipart(_) => "multipart/mixed",
+            PartType::Binary(_) | PartType::InlineBinary(_) => "application/octet-stream",
+        }
+        .to_string(),
+    }
+}
+
+/// Content type of a multipart including its boundary, needed to make sense of the raw body
+fn multipart_content_type(part: &MessagePart) -> String {
+    let boundary = part.content_type().and_then(|ct| ct.attribute("boundary"));
+    match boundary {
+        Some(boundary) => format!("{}; boundary=\"{}\"", content_type(part), boundary),
+        None => content_type(part),
+    }
+}
+
+/// Raw, undecoded body of a part
+fn raw_body<'a>(message: &'a Message, part: &MessagePart) -> &'a [u8] {
+    raw_slice(message, part.offset_body, part.offset_end)
+}
+
+/// Bytes of `message` between two offsets of its parts or headers
+///
+/// The offsets inside an attached message that is not transfer-encoded are relative to
+/// the outermost message, while its raw message only holds the attached message itself.
+pub fn raw_slice<'a>(message: &'a Message, start: u32, end: u32) -> &'a [u8] {
+    let base = message.parts.first().map_or(0, |part| part.offset_header);
+    let start = start.saturating_sub(base) as usize;
+    let end = end.saturating_sub(base) as usize;
+    message.raw_message().get(start..end).unwrap_or_default()
+}
+
+/// Messages attached to `message` as `message/rfc822` parts, in message order
+pub fn attached_messages<'a, 'x>(
+    message: &'a Message<'x>,
+) -> impl Iterator<Item = &'a Message<'x>> {
+    message.parts.iter().filter_map(|part| match &part.body {
+        PartType::Message(nested) => Some(nested),
+        _ => None,
+    })
+}
+
+/// Find an attached message by its path, such as `2` for the second attached message
+/// and `2.1` for the first message attached to that one
+pub fn find_attached_message<'a, 'x>(
+    message: &'a Message<'x>,
+    path: &str,
+) -> Option<&'a Message<'x>> {
+    let mut message = message;
+    for segment in path.split('.') {
+        let index: usize = segment.parse().ok()?;
+        message = attached_messages(message).nth(index.checked_sub(1)?)?;
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    const MESSAGE: &[u8] = b"From: sender@example.com\r\n\
+        Subject: Tree\r\n\
+        Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+        \r\n\
+        --outer\r\n\
+        Content-Type: multipart/related; boundary=\"inner\"\r\n\
+        \r\n\
+        --inner\r\n\
+        Content-Type: text/html; charset=iso-8859-1\r\n\
+        Content-Transfer-Encoding: quoted-printable\r\n\
+        \r\n\
+        <p>Caf=E9</p><img src=3D\"cid:logo@example.com\">\r\n\
+        --inner\r\n\
+        Content-Type: image/png\r\n\
+        Content-ID: <logo@example.com>\r\n\
+        Content-Disposition: inline\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        iVBORw0KGgo=\r\n\
+        --inner--\r\n\
+        --outer\r\n\
+        Content-Type: text/plain\r\n\
+        Content-Disposition: attachment; filename=\"notes.txt\"\r\n\
+        \r\n\
+        notes\r\n\
+        --outer--\r\n";
+
+    #[test]
+    fn test_mime_tree() {
+        let message = MessageParser::default().parse(MESSAGE).unwrap();
+        let tree = mime_tree(&message);
+
+        assert_eq!(tree.path, "1");
+        assert_eq!(tree.content_type, "multipart/mixed");
+        assert_eq!(tree.children.len(), 2);
+
+        let related = &tree.children[0];
+        assert_eq!(related.path, "1.1");
+        assert_eq!(related.content_type, "multipart/related");
+
+        let html = &related.children[0];
+        assert_eq!(html.path, "1.1.1");
+        assert_eq!(html.content_type, "text/html");
+        assert_eq!(html.charset.as_deref(), Some("iso-8859-1"));
+        assert_eq!(html.transfer_encoding.as_deref(), Some("quoted-printable"));
+
+        let image = &related.children[1];
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(image.content_id.as_deref(), Some("logo@example.com"));
+        assert_eq!(image.disposition.as_deref(), Some("inline"));
+        assert_eq!(image.size, 8);
+
+        let notes = &tree.children[1];
+        assert_eq!(notes.path, "1.2");
+        assert_eq!(notes.disposition.as_deref(), Some("attachment"));
+        assert_eq!(notes.filename.as_deref(), Some("notes.txt"));
+        assert_eq!(notes.size, 5);
+    }
+
+    #[test]
+    fn test_find_part() {
+        let message = MessageParser::default().parse(MESSAGE).unwrap();
+
+        let html = find_part(&message, "1.1.1").unwrap();
+        assert_eq!(html.content_type, "text/html; charset=utf-8");
+        assert!(String::from_utf8(html.data).unwrap().contains("Café"));
+
+        let image = find_part(&message, "1.1.2").unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(image.data, b"\x89PNG\r\n\x1a\n");
+
+        let notes = find_part(&message, "1.2").unwrap();
+        assert_eq!(notes.filename.as_deref(), Some("notes.txt"));
+
+        let related = find_part(&message, "1.1").unwrap();
+        assert_eq!(
+            related.content_type,
+            "multipart/related; boundary=\"inner\""
+        );
+        assert!(related.data.starts_with(b"--inner\r\n"));
+
+        assert!(find_part(&message, "1").is_some());
+        for path in ["", "2", "1.0", "1.3", "1.2.1", "1.x"] {
+            assert!(find_part(&message, path).is_none(), "path: {}", path);
+        }
+    }
+
+    #[test]
+    fn test_attached_messages() {
+        let raw = b"Subject: Outer\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: message/rfc822\r\n\
+            \r\n\
+            Subject: Inline\r\n\
+            Content-Type: multipart/mixed; boundary=\"c\"\r\n\
+            \r\n\
+            --c\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            text\r\n\
+            --c--\r\n\
+            --b\r\n\
+            Content-Type: message/rfc822\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            U3ViamVjdDogRW5jb2RlZA0KDQpFbmNvZGVkIGJvZHkNCg==\r\n\
+            --b--\r\n";
+        let message = MessageParser::default().parse(&raw[..]).unwrap();
+
+        let subjects: Vec<_> = attached_messages(&message)
+            .map(|nested| nested.subject().unwrap())
+            .collect();
+        assert_eq!(subjects, vec!["Inline", "Encoded"]);
+
+        let inline = find_attached_message(&message, "1").unwrap();
+        assert!(inline.raw_message().starts_with(b"Subject: Inline\r\n"));
+        let header = &inline.parts[0].headers[0];
+        assert_eq!(
+            raw_slice(inline, header.offset_field, header.offset_end),
+            b"Subject: Inline\r\n"
+        );
+        let text = find_part(inline, "1.1").unwrap();
+        assert_eq!(text.data, b"text");
+        assert!(find_part(inline, "1").unwrap().data.starts_with(b"--c\r\n"));
+
+        let encoded = find_attached_message(&message, "2").unwrap();
+        assert_eq!(
+            encoded.raw_message(),
+            b"Subject: Encoded\r\n\r\nEncoded body\r\n"
+        );
+
+        assert!(find_attached_message(&message, "3").is_none());
+        assert!(find_attached_message(&message, "1.1").is_none());
+    }
+
+    #[test]
+    fn test_find_content_id() {
+        let message = MessageParser::default().parse(MESSAGE).unwrap();
+
+        let image = find_content_id(&message, "logo@example.com").unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(image.data, b"\x89PNG\r\n\x1a\n");
+
+        assert!(find_content_id(&message, "<logo@example.com>").is_none());
+        assert!(find_content_id(&message, "missing@example.com").is_none());
+    }
+}
@@ -1,10 +1,15 @@
+//! Data structures for email representation and application state
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::RwLock;
 use tokio::sync::broadcast;
 
-/// Data structures for email representation and application state
+use crate::retention::RetentionPolicy;
+use crate::search::SearchIndex;
+use crate::storage::Storage;
+use crate::thread::ThreadIndex;
+use crate::{html, thread};
 
 /// Represents an email message with all its components
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,20 +18,422 @@ pub struct Email {
     pub id: String,
     /// Timestamp when the email was received
     pub received_at: DateTime<Utc>,
-    /// Email sender address
+    /// Email sender address from the SMTP envelope (`MAIL FROM`)
     pub from: String,
-    /// List of recipient email addresses
+    /// List of recipient email addresses from the SMTP envelope (`RCPT TO`)
     pub to: Vec<String>,
+    /// Addresses from the message headers
+    #[serde(default)]
+    pub addresses: MessageAddresses,
+    /// Envelope recipients that appear in neither the `To` nor the `Cc` header
+    #[serde(default)]
+    pub bcc: Vec<String>,
     /// Email subject line
     pub subject: String,
     /// Plain text version of the email body (if available)
     pub text_body: Option<String>,
     /// HTML version of the email body (if available)
     pub html_body: Option<String>,
-    /// Map of email headers
+    /// Map of email headers; only the last value of a repeated header is kept
     pub headers: HashMap<String, String>,
+    /// All header fields in message order, including repeated ones
+    #[serde(default)]
+    pub header_list: Vec<Header>,
+    /// `Message-ID` header without angle brackets
+    #[serde(default)]
+    pub message_id: Option<String>,
+    /// Message IDs from the `In-Reply-To` header
+    #[serde(default)]
+    pub in_reply_to: Vec<String>,
+    /// Message IDs from the `References` header, oldest first
+    #[serde(default)]
+    pub references: Vec<String>,
+    /// ID of the conversation the email belongs to, assigned when the email is stored
+    #[serde(default)]
+    pub thread_id: String,
     /// List of email attachments
     pub attachments: Vec<Attachment>,
+    /// MIME structure of the message, if it could be parsed
+    #[serde(default)]
+    pub mime: Option<MimePart>,
+    /// Emails attached as `message/rfc822` parts, such as forwarded messages and bounced
+    /// originals; their IDs are this email's ID followed by `.1`, `.2` and so on
+    #[serde(default)]
+    pub attached_messages: Vec<Email>,
+    /// ID of the email this email is attached to
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    /// Delivery status, if the email is a delivery status notification
+    #[serde(default)]
+    pub dsn: Option<Dsn>,
+    /// Calendar invitation or cancellation, if the email carries a `text/calendar` part
+    #[serde(default)]
+    pub calendar: Option<Calendar>,
+    /// TLS parameters of the SMTP session, if the email was received over TLS
+    #[serde(default)]
+    pub tls: Option<TlsInfo>,
+    /// Username the sending client authenticated as with SMTP AUTH
+    #[serde(default)]
+    pub auth_user: Option<String>,
+    /// DSN parameters given in `MAIL FROM` and `RCPT TO`, if any
+    #[serde(default)]
+    pub dsn_request: Option<DsnRequest>,
+    /// Name of the mailbox the email was delivered to
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    /// Size of the raw message in bytes
+    #[serde(default)]
+    pub size: usize,
+}
+
+/// An empty email in the default mailbox, received at the Unix epoch
+impl Default for Email {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            received_at: DateTime::default(),
+            from: String::new(),
+            to: Vec::new(),
+            addresses: MessageAddresses::default(),
+            bcc: Vec::new(),
+            subject: String::new(),
+            text_body: None,
+            html_body: None,
+            headers: HashMap::new(),
+            header_list: Vec::new(),
+            message_id: None,
+            in_reply_to: Vec::new(),
+            references: Vec::new(),
+            thread_id: String::new(),
+            attachments: Vec::new(),
+            mime: None,
+            attached_messages: Vec::new(),
+            parent_id: None,
+            dsn: None,
+            calendar: None,
+            tls: None,
+            auth_user: None,
+            dsn_request: None,
+            mailbox: default_mailbox(),
+            size: 0,
+        }
+    }
+}
+
+/// Parsed address headers of a message
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageAddresses {
+    /// `From` header
+    pub from: Vec<EmailAddress>,
+    /// `Sender` header
+    pub sender: Vec<EmailAddress>,
+    /// `Reply-To` header
+    pub reply_to: Vec<EmailAddress>,
+    /// `To` header
+    pub to: Vec<EmailAddress>,
+    /// `Cc` header
+    pub cc: Vec<EmailAddress>,
+    /// `Bcc` header
+    pub bcc: Vec<EmailAddress>,
+}
+
+/// A mailbox from an address header
+///
+/// Members of an RFC 5322 group carry the group name; an empty group such as
+/// `undisclosed-recipients:;` is a single entry with only the group name set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EmailAddress {
+    /// Display name
+    pub name: Option<String>,
+    /// Email address, missing for malformed entries
+    pub address: Option<String>,
+    /// Name of the group the mailbox belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// A single header field as it appears in the message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    /// Field name as written in the message
+    pub name: String,
+    /// Field value as written in the message, including folding and encoded words
+    pub raw: String,
+    /// Decoded field value
+    pub value: String,
+}
+
+/// Name of the mailbox receiving mail that matches no AUTH user or domain rule
+pub const DEFAULT_MAILBOX: &str = "default";
+
+/// Serde default for [`Email::mailbox`]
+fn default_mailbox() -> String {
+    DEFAULT_MAILBOX.to_string()
+}
+
+/// Summary of a mailbox for the mailbox list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MailboxSummary {
+    /// Mailbox name
+    pub name: String,
+    /// Number of emails in the mailbox
+    pub count: usize,
+}
+
+/// A node of the MIME structure of an email
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MimePart {
+    /// Position in the tree: `1` for the root, `1.2` for its second child, and so on
+    pub path: String,
+    /// MIME type without parameters, such as `multipart/alternative`
+    pub content_type: String,
+    /// `charset` parameter of the content type
+    pub charset: Option<String>,
+    /// Content-Transfer-Encoding as declared in the part
+    pub transfer_encoding: Option<String>,
+    /// Content-Disposition type, such as `inline` or `attachment`
+    pub disposition: Option<String>,
+    /// File name from the Content-Disposition or Content-Type parameters
+    pub filename: Option<String>,
+    /// Content-ID without angle brackets
+    pub content_id: Option<String>,
+    /// Size of the decoded content in bytes (of the raw body for multiparts)
+    pub size: usize,
+    /// Child parts of a multipart, or the body of an attached message
+    pub children: Vec<MimePart>,
+}
+
+/// Delivery status notification (RFC 3464), such as a bounce
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Dsn {
+    /// `Reporting-MTA` field, such as `dns; mx.example.com`
+    pub reporting_mta: Option<String>,
+    /// `Original-Envelope-Id` field, the ENVID given by the original sender
+    pub original_envelope_id: Option<String>,
+    /// `Arrival-Date` field
+    pub arrival_date: Option<String>,
+    /// Status of each recipient
+    pub recipients: Vec<DsnRecipient>,
+}
+
+/// Delivery status of a single recipient in a DSN
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DsnRecipient {
+    /// Address from the `Final-Recipient` field, without the address type
+    pub final_recipient: Option<String>,
+    /// Address from the `Original-Recipient` field, without the address type
+    pub original_recipient: Option<String>,
+    /// `Action` field in lowercase: `failed`, `delayed`, `delivered`, `relayed` or `expanded`
+    pub action: Option<String>,
+    /// `Status` field, an enhanced status code such as `5.1.1`
+    pub status: Option<String>,
+    /// `Diagnostic-Code` field, such as `smtp; 550 5.1.1 User unknown`
+    pub diagnostic_code: Option<String>,
+    /// `Remote-MTA` field
+    pub remote_mta: Option<String>,
+}
+
+/// iCalendar object (RFC 5545) carried by an email, such as a meeting invitation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Calendar {
+    /// iTIP method (RFC 5546), such as `REQUEST`, `REPLY` or `CANCEL`
+    pub method: Option<String>,
+    /// Events of the calendar
+    pub events: Vec<CalendarEvent>,
+}
+
+/// A `VEVENT` of a calendar
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalendarEvent {
+    /// `UID` property, shared by all updates of the same event
+    pub uid: Option<String>,
+    /// `SUMMARY` property, the event title
+    pub summary: Option<String>,
+    /// `LOCATION` property
+    pub location: Option<String>,
+    /// `STATUS` property, such as `CONFIRMED` or `CANCELLED`
+    pub status: Option<String>,
+    /// `SEQUENCE` property, incremented by each update of the event
+    pub sequence: Option<u32>,
+    /// `DTSTART` property
+    pub start: Option<CalendarTime>,
+    /// `DTEND` property
+    pub end: Option<CalendarTime>,
+    /// `ORGANIZER` property
+    pub organizer: Option<CalendarAttendee>,
+    /// `ATTENDEE` properties
+    pub attendees: Vec<CalendarAttendee>,
+}
+
+/// A date or date-time of a calendar event
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalendarTime {
+    /// Value as written, such as `20240115T100000`, `20240115T100000Z` or `20240115`
+    pub value: String,
+    /// `TZID` parameter naming the time zone of a local time
+    pub tzid: Option<String>,
+    /// Whether the value is a date without a time (an all-day event)
+    pub all_day: bool,
+    /// The value in UTC, for values written in UTC
+    pub utc: Option<DateTime<Utc>>,
+}
+
+/// The organizer or an attendee of a calendar event
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalendarAttendee {
+    /// Email address, without the `mailto:` scheme
+    pub address: String,
+    /// `CN` parameter, the display name
+    pub name: Option<String>,
+    /// `ROLE` parameter, such as `REQ-PARTICIPANT`
+    pub role: Option<String>,
+    /// `PARTSTAT` parameter, such as `NEEDS-ACTION` or `ACCEPTED`
+    pub partstat: Option<String>,
+    /// `RSVP` parameter: whether a reply is expected
+    pub rsvp: bool,
+}
+
+/// DSN parameters of an SMTP transaction (RFC 3461)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DsnRequest {
+    /// `RET` parameter of `MAIL FROM`: `FULL` or `HDRS`
+    pub ret: Option<String>,
+    /// `ENVID` parameter of `MAIL FROM`, decoded from xtext
+    pub envid: Option<String>,
+    /// Recipients given with `NOTIFY` or `ORCPT` parameters, in `RCPT TO` order
+    pub recipients: Vec<DsnRecipientRequest>,
+}
+
+impl DsnRequest {
+    /// Check whether no DSN parameter was given
+    pub fn is_empty(&self) -> bool {
+        self.ret.is_none() && self.envid.is_none() && self.recipients.is_empty()
+    }
+}
+
+/// DSN parameters of a single `RCPT TO`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DsnRecipientRequest {
+    /// Forward-path of the recipient
+    pub address: String,
+    /// `NOTIFY` conditions: `NEVER`, or any of `SUCCESS`, `FAILURE` and `DELAY`
+    pub notify: Vec<String>,
+    /// `ORCPT` parameter as `<address type>;<address>`, with the address decoded from xtext
+    pub orcpt: Option<String>,
+}
+
+/// Maximum length of [`EmailSummary::snippet`] in characters
+pub const SNIPPET_LENGTH: usize = 200;
+
+/// Lightweight view of an email for lists and WebSocket events, without bodies, headers or
+/// attachment contents; the full email is available from `/api/emails/{id}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmailSummary {
+    /// Email ID
+    pub id: String,
+    /// Timestamp when the email was received
+    pub received_at: DateTime<Utc>,
+    /// Envelope sender (`MAIL FROM`)
+    pub from: String,
+    /// Envelope recipients (`RCPT TO`)
+    pub to: Vec<String>,
+    /// Email subject
+    pub subject: String,
+    /// Mailbox the email was delivered to
+    pub mailbox: String,
+    /// ID of the conversation the email belongs to
+    pub thread_id: String,
+    /// Size of the raw message in bytes
+    pub size: usize,
+    /// Number of attachments
+    pub attachment_count: usize,
+    /// Start of the text body (or of the text of the HTML body), with whitespace collapsed
+    pub snippet: String,
+    /// Properties of the full email, for badges and filtering
+    pub flags: EmailFlags,
+}
+
+/// Properties of an email shown in its summary
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EmailFlags {
+    /// The email has an HTML body
+    pub html: bool,
+    /// The email had hidden (BCC) recipients
+    pub bcc: bool,
+    /// The email is a delivery status notification
+    pub dsn: bool,
+    /// The email carries a calendar invitation
+    pub calendar: bool,
+    /// The email has attached messages
+    pub attached_messages: bool,
+    /// The email was received over TLS
+    pub tls: bool,
+    /// The email was sent by a client authenticated with SMTP AUTH
+    pub authenticated: bool,
+}
+
+impl From<&Email> for EmailSummary {
+    fn from(email: &Email) -> Self {
+        let body = match (&email.text_body, &email.html_body) {
+            (Some(text), _) => text.clone(),
+            (None, Some(html)) => html::to_text(html),
+            (None, None) => String::new(),
+        };
+        let snippet = body
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(SNIPPET_LENGTH)
+            .collect();
+
+        Self {
+            id: email.id.clone(),
+            received_at: email.received_at,
+            from: email.from.clone(),
+            to: email.to.clone(),
+            subject: email.subject.clone(),
+            mailbox: email.mailbox.clone(),
+            thread_id: thread::thread_id(email).to_string(),
+            size: email.size,
+            attachment_count: email.attachments.len(),
+            snippet,
+            flags: EmailFlags {
+                html: email.html_body.is_some(),
+                bcc: !email.bcc.is_empty(),
+                dsn: email.dsn.is_some(),
+                calendar: email.calendar.is_some(),
+                attached_messages: !email.attached_messages.is_empty(),
+                tls: email.tls.is_some(),
+                authenticated: email.auth_user.is_some(),
+            },
+        }
+    }
+}
+
+/// Summary of a conversation, as listed by `/api/threads`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    /// Thread ID, shared by all emails of the conversation
+    pub id: String,
+    /// Subject of the first email
+    pub subject: String,
+    /// IDs of the emails, oldest first
+    pub email_ids: Vec<String>,
+    /// When the first email was received
+    pub first_received_at: DateTime<Utc>,
+    /// When the latest email was received
+    pub last_received_at: DateTime<Utc>,
+}
+
+/// A conversation with all of its emails, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thread {
+    /// Thread ID, shared by all emails of the conversation
+    pub id: String,
+    /// Emails of the conversation, oldest first
+    pub emails: Vec<Email>,
 }
 
 /// Represents an email attachment
@@ -45,12 +452,163 @@ pub struct Attachment {
     pub data: Option<Vec<u8>>,
 }
 
+/// TLS parameters negotiated for the SMTP session that delivered an email
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// Negotiated protocol version (e.g. `TLSv1.3`)
+    pub version: String,
+    /// Negotiated cipher suite (e.g. `TLS13_AES_256_GCM_SHA384`)
+    pub cipher: String,
+    /// Whether TLS was negotiated on connect (SMTPS) instead of through STARTTLS
+    #[serde(default)]
+    pub implicit: bool,
+}
+
 /// Application state shared between SMTP and HTTP servers
 pub struct AppState {
-    /// Thread-safe storage for captured emails
-    pub emails: RwLock<Vec<Email>>,
-    /// Broadcast channel for real-time notifications about new emails
-    pub tx: broadcast::Sender<Email>,
+    /// Storage backend for captured emails
+    pub storage: Box<dyn Storage>,
+    /// Broadcast channel for real-time notifications about new and deleted emails
+    pub tx: broadcast::Sender<EmailEvent>,
+    /// Limits applied to the stored emails
+    pub retention: RetentionPolicy,
+    /// Full-text index over the stored emails
+    pub index: SearchIndex,
+    /// Message IDs of the stored emails, to thread new emails
+    pub threads: ThreadIndex,
+}
+
+impl AppState {
+    /// Create the application state on top of a storage backend, without retention limits
+    ///
+    /// The search and thread indexes are built from the emails already in the storage.
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        let (tx, _) = broadcast::channel(100);
+        let emails = storage.list();
+        let index = SearchIndex::new(&emails);
+        let threads = ThreadIndex::new(&emails);
+        Self {
+            storage,
+            tx,
+            retention: RetentionPolicy::default(),
+            index,
+            threads,
+        }
+    }
+
+    /// Drop deleted emails from the indexes and broadcast their deletion to WebSocket clients
+    pub fn notify_deleted(&self, ids: Vec<String>) {
+        for id in ids {
+            self.index.remove(&id);
+            self.threads.remove(&id);
+            let _ = self.tx.send(EmailEvent::Deleted { id });
+        }
+    }
+
+    /// Drop the emails removed by clearing a mailbox (or all mailboxes) from the indexes
+    /// and broadcast the clear as a single event
+    pub fn notify_cleared(&self, mailbox: Option<String>, ids: Vec<String>) {
+        for id in &ids {
+            self.index.remove(id);
+            self.threads.remove(id);
+        }
+        let _ = self.tx.send(EmailEvent::Cleared { mailbox, ids });
+    }
+}
+
+/// Change to the stored emails, broadcast to WebSocket clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmailEvent {
+    /// A new email was captured
+    Created {
+        /// The captured email
+        email: Box<Email>,
+    },
+    /// An email was deleted by a client or evicted by the retention policy
+    Deleted {
+        /// ID of the deleted email
+        id: String,
+    },
+    /// A mailbox, or every mailbox, was cleared by a client
+    Cleared {
+        /// Cleared mailbox; all mailboxes when `None`
+        mailbox: Option<String>,
+        /// IDs of the deleted emails
+        ids: Vec<String>,
+    },
+}
+
+/// Version of the WebSocket protocol, sent with every event
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Event sent to a WebSocket client, wrapped in an [`EventEnvelope`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// The stored emails matching the subscription, oldest first, sent on connect, after
+    /// each subscription change and after missed events; replaces the client's list
+    Snapshot {
+        /// Summaries of the matching emails
+        emails: Vec<EmailSummary>,
+    },
+    /// A new email matching the subscription was captured
+    Created {
+        /// Summary of the captured email
+        email: EmailSummary,
+    },
+    /// An email was deleted by a client or evicted by the retention policy
+    Deleted {
+        /// ID of the deleted email
+        id: String,
+    },
+    /// A mailbox, or every mailbox, was cleared by a client
+    Cleared {
+        /// Cleared mailbox; all mailboxes when `None`
+        mailbox: Option<String>,
+        /// IDs of the deleted emails
+        ids: Vec<String>,
+    },
+    /// A message from the client could not be understood
+    Error {
+        /// Description of the problem
+        message: String,
+    },
+}
+
+impl From<&EmailEvent> for ClientEvent {
+    fn from(event: &EmailEvent) -> Self {
+        match event {
+            EmailEvent::Created { email } => Self::Created {
+                email: EmailSummary::from(email.as_ref()),
+            },
+            EmailEvent::Deleted { id } => Self::Deleted { id: id.clone() },
+            EmailEvent::Cleared { mailbox, ids } => Self::Cleared {
+                mailbox: mailbox.clone(),
+                ids: ids.clone(),
+            },
+        }
+    }
+}
+
+/// WebSocket message from the server: an event tagged with the protocol version, such as
+/// `{"version": 1, "type": "deleted", "id": "..."}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    /// Protocol version, [`PROTOCOL_VERSION`]
+    pub version: u32,
+    /// The event, whose `type` and fields are inlined
+    #[serde(flatten)]
+    pub event: ClientEvent,
+}
+
+impl From<ClientEvent> for EventEnvelope {
+    fn from(event: ClientEvent) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            event,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -69,8 +627,7 @@ mod tests {
             subject: "Test Subject".to_string(),
             text_body: Some("This is a test email".to_string()),
             html_body: Some("<p>This is a test email</p>".to_string()),
-            headers: HashMap::new(),
-            attachments: Vec::new(),
+            ..Default::default()
         };
 
         assert_eq!(email.from, "sender@example.com");
@@ -84,6 +641,59 @@ mod tests {
         assert!(email.attachments.is_empty());
     }
 
+    #[test]
+    fn test_email_summary() {
+        let mut email = Email {
+            id: "email-1".to_string(),
+            html_body: Some("<h1>Hello</h1>\n<p>This   week:</p>".to_string()),
+            auth_user: Some("team-a".to_string()),
+            mailbox: "team-a".to_string(),
+            size: 1234,
+            ..Default::default()
+        };
+
+        let summary = EmailSummary::from(&email);
+        assert_eq!(summary.snippet, "Hello This week:");
+        assert_eq!(summary.thread_id, "email-1");
+        assert_eq!(summary.size, 1234);
+        assert_eq!(
+            summary.flags,
+            EmailFlags {
+                html: true,
+                authenticated: true,
+                ..Default::default()
+            }
+        );
+
+        // The text body is preferred, and cut to the snippet length
+        email.text_body = Some("word ".repeat(100));
+        let summary = EmailSummary::from(&email);
+        assert_eq!(summary.snippet.chars().count(), SNIPPET_LENGTH);
+        assert!(summary.snippet.starts_with("word word"));
+    }
+
+    #[test]
+    fn test_event_envelope() {
+        let envelope = EventEnvelope::from(ClientEvent::Cleared {
+            mailbox: Some("team-a".to_string()),
+            ids: vec!["email-1".to_string()],
+        });
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": 1,
+                "type": "cleared",
+                "mailbox": "team-a",
+                "ids": ["email-1"],
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<EventEnvelope>(json).unwrap(),
+            envelope
+        );
+    }
+
     #[test]
     fn test_attachment_creation() {
         let attachment = Attachment {
@@ -0,0 +1,209 @@
+//! Retention limits for captured emails
+//!
+//! Emails are pruned after every insert and periodically in the background, so that
+//! the age limit also applies while no new mail arrives. Every eviction is broadcast
+//! to WebSocket clients.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::models::AppState;
+use crate::storage::StoredEmail;
+
+/// How often the background task prunes the stored emails
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits on the stored emails; unset limits do not apply
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Keep at most this many of the newest emails
+    pub max_count: Option<usize>,
+    /// Drop emails received longer ago than this
+    pub max_age: Option<Duration>,
+    /// Keep at most this many stored bytes (raw messages and decoded attachments), newest first
+    pub max_bytes: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Check whether no limit is configured
+    pub fn is_unlimited(&self) -> bool {
+        self.max_count.is_none() && self.max_age.is_none() && self.max_bytes.is_none()
+    }
+
+    /// Select the emails to evict
+    ///
+    /// `emails` must be ordered oldest first. Emails are kept newest first until a limit
+    /// is reached; everything older than that is evicted.
+    pub fn select_evictions(&self, emails: &[StoredEmail], now: DateTime<Utc>) -> HashSet<String> {
+        let cutoff = self
+            .max_age
+            .and_then(|age| chrono::Duration::from_std(age).ok())
+            .map(|age| now - age);
+
+        let mut evicted = HashSet::new();
+        let mut kept_count = 0;
+        let mut kept_bytes = 0;
+        let mut full = false;
+        for email in emails.iter().rev() {
+            full = full
+                || cutoff.is_some_and(|cutoff| email.received_at < cutoff)
+                || self.max_count.is_some_and(|max| kept_count >= max)
+                || self
+                    .max_bytes
+                    .is_some_and(|max| kept_bytes + email.size > max);
+
+            if full {
+                evicted.insert(email.id.clone());
+            } else {
+                kept_count += 1;
+                kept_bytes += email.size;
+            }
+        }
+        evicted
+    }
+}
+
+/// Evict the emails exceeding the retention limits and broadcast their deletion
+pub fn prune(state: &AppState) -> io::Result<()> {
+    if state.retention.is_unlimited() {
+        return Ok(());
+    }
+
+    let evicted = state
+        .retention
+        .select_evictions(&state.storage.usage(), Utc::now());
+    if evicted.is_empty() {
+        return Ok(());
+    }
+
+    // Emails removed before a failure are gone from storage, so broadcast them either way
+    match state
+        .storage
+        .delete_where(&|email| evicted.contains(&email.id))
+    {
+        Ok(deleted) => {
+            info!(
+                "Evicted {} emails exceeding the retention limits",
+                deleted.len()
+            );
+            state.notify_deleted(deleted);
+            Ok(())
+        }
+        Err(e) => {
+            state.notify_deleted(e.deleted.clone());
+            Err(e.into())
+        }
+    }
+}
+
+/// Prune the stored emails every [`PRUNE_INTERVAL`] until the application stops
+pub async fn run_pruner(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = prune(&state) {
+            warn!("Failed to prune emails: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Email, EmailEvent};
+    use crate::storage::MemoryStorage;
+
+    // Helper function to create stored emails received one minute apart, oldest first
+    fn create_test_emails(now: DateTime<Utc>, sizes: &[usize]) -> Vec<StoredEmail> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| StoredEmail {
+                id: format!("email-{}", i),
+                received_at: now - chrono::Duration::minutes((sizes.len() - i) as i64),
+                size,
+            })
+            .collect()
+    }
+
+    fn ids(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_unlimited_policy_keeps_everything() {
+        let now = Utc::now();
+        let emails = create_test_emails(now, &[10, 20, 30]);
+        assert!(RetentionPolicy::default().is_unlimited());
+        assert!(
+            RetentionPolicy::default()
+                .select_evictions(&emails, now)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_max_count_keeps_newest() {
+        let now = Utc::now();
+        let emails = create_test_emails(now, &[10, 20, 30]);
+        let policy = RetentionPolicy {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(policy.select_evictions(&emails, now), ids(&["email-0"]));
+    }
+
+    #[test]
+    fn test_max_age() {
+        let now = Utc::now();
+        let emails = create_test_emails(now, &[10, 20, 30]);
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(150)),
+            ..Default::default()
+        };
+        // Received 3, 2 and 1 minutes ago
+        assert_eq!(policy.select_evictions(&emails, now), ids(&["email-0"]));
+    }
+
+    #[test]
+    fn test_max_bytes_evicts_everything_older() {
+        let now = Utc::now();
+        let emails = create_test_emails(now, &[5, 20, 30, 40]);
+        let policy = RetentionPolicy {
+            max_bytes: Some(75),
+            ..Default::default()
+        };
+        // email-1 no longer fits, so the smaller email-0 is evicted as well
+        assert_eq!(
+            policy.select_evictions(&emails, now),
+            ids(&["email-0", "email-1"])
+        );
+    }
+
+    #[test]
+    fn test_prune_broadcasts_evictions() {
+        let email = |id: &str| Email {
+            id: id.to_string(),
+            received_at: Utc::now(),
+            size: 100,
+            ..Default::default()
+        };
+        let storage = MemoryStorage::new(vec![email("old"), email("new")]);
+        let mut state = AppState::new(Box::new(storage));
+        state.retention.max_count = Some(1);
+        let mut rx = state.tx.subscribe();
+
+        prune(&state).unwrap();
+
+        let remaining: Vec<String> = state.storage.list().into_iter().map(|e| e.id).collect();
+        assert_eq!(remaining, vec!["new".to_string()]);
+        match rx.try_recv().unwrap() {
+            EmailEvent::Deleted { id } => assert_eq!(id, "old"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
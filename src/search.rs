@@ -0,0 +1,459 @@
+//! Full-text search index
+//!
+//! An inverted index from terms to the emails containing them, kept in memory next to the
+//! storage. It is updated when an email is stored or deleted, and covers the subject, the
+//! sender and recipients, the text body, the text of the HTML body and text attachments.
+//! Every occurrence is recorded with its field and position, which allows phrase queries
+//! and queries limited to a single field.
+
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::html;
+use crate::models::{Email, EmailAddress};
+
+/// Part of an email covered by the index, selected in queries with a `name:` prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// Subject
+    Subject,
+    /// Envelope sender and From header
+    From,
+    /// Envelope recipients and To and Cc headers
+    To,
+    /// Text body and text of the HTML body
+    Body,
+    /// Text attachments
+    Attachment,
+}
+
+impl Field {
+    /// Field selected by a query prefix such as `subject`
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.to_ascii_lowercase().as_str() {
+            "subject" => Some(Self::Subject),
+            "from" => Some(Self::From),
+            "to" => Some(Self::To),
+            "body" => Some(Self::Body),
+            "attachment" => Some(Self::Attachment),
+            _ => None,
+        }
+    }
+}
+
+/// One part of a query: a term or phrase, optionally limited to a field
+#[derive(Debug, PartialEq)]
+struct Clause {
+    /// Field the terms must appear in; any field when `None`
+    field: Option<Field>,
+    /// Lowercase words that must appear in a row
+    terms: Vec<String>,
+}
+
+/// Inverted index over the stored emails
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    inner: RwLock<Index>,
+}
+
+/// Postings of the indexed emails, guarded by the lock of [`SearchIndex`]
+#[derive(Debug, Default)]
+struct Index {
+    /// Term, then email ID, then field and position of each occurrence
+    postings: HashMap<String, HashMap<String, Vec<(Field, u32)>>>,
+    /// Terms of each email, to remove it again
+    terms: HashMap<String, HashSet<String>>,
+    /// Receive time of each email, to order the results
+    received_at: HashMap<String, DateTime<Utc>>,
+}
+
+impl SearchIndex {
+    /// Create an index over the given emails
+    pub fn new<'a>(emails: impl IntoIterator<Item = &'a Email>) -> Self {
+        let index = Self::default();
+        for email in emails {
+            index.insert(email);
+        }
+        index
+    }
+
+    /// Add an email to the index, replacing any earlier version of it
+    pub fn insert(&self, email: &Email) {
+        let mut index = self.inner.write().unwrap();
+        index.remove(&email.id);
+
+        let mut terms = HashSet::new();
+        for (field, texts) in fields(email) {
+            let mut position = 0;
+            for text in texts {
+                for term in tokenize(&text) {
+                    index
+                        .postings
+                        .entry(term.clone())
+                        .or_default()
+                        .entry(email.id.clone())
+                        .or_default()
+                        .push((field, position));
+                    terms.insert(term);
+                    position += 1;
+                }
+                // Keep phrases from matching across two values of the same field
+                position += 1;
+            }
+        }
+        index.terms.insert(email.id.clone(), terms);
+        index
+            .received_at
+            .insert(email.id.clone(), email.received_at);
+    }
+
+    /// Remove an email from the index
+    pub fn remove(&self, id: &str) {
+        self.inner.write().unwrap().remove(id);
+    }
+
+    /// Find the IDs of the emails matching all clauses of a query
+    ///
+    /// A clause is a single word or a `"quoted phrase"`, optionally prefixed with the field it
+    /// must appear in, such as `subject:invoice` or `from:"Alice Smith"`. Words are matched
+    /// whole and ignoring case. A query without any words matches nothing. The newest
+    /// email comes first, with emails received at the same time ordered by ID.
+    pub fn search(&self, query: &str) -> Vec<String> {
+        let index = self.inner.read().unwrap();
+        let mut clauses = parse_query(query).into_iter();
+        let Some(first) = clauses.next() else {
+            return Vec::new();
+        };
+
+        let mut ids = index.matches(&first);
+        for clause in clauses {
+            if ids.is_empty() {
+                break;
+            }
+            let matches = index.matches(&clause);
+            ids.retain(|id| matches.contains(id));
+        }
+
+        let mut ids: Vec<String> = ids.into_iter().collect();
+        ids.sort_by(|a, b| {
+            let received_at = |id: &String| Reverse(index.received_at.get(id).copied());
+            received_at(a).cmp(&received_at(b)).then_with(|| a.cmp(b))
+        });
+        ids
+    }
+}
+
+impl Index {
+    /// Remove an email, dropping the terms no other email contains
+    fn remove(&mut self, id: &str) {
+        self.received_at.remove(id);
+        let Some(terms) = self.terms.remove(id) else {
+            return;
+        };
+        for term in terms {
+            if let Some(emails) = self.postings.get_mut(&term) {
+                emails.remove(id);
+                if emails.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// IDs of the emails containing the terms of a clause in a row, within one field
+    fn matches(&self, clause: &Clause) -> HashSet<String> {
+        let Some((first, rest)) = clause.terms.split_first() else {
+            return HashSet::new();
+        };
+        let Some(emails) = self.postings.get(first) else {
+            return HashSet::new();
+        };
+
+        emails
+            .iter()
+            .filter(|(id, occurrences)| {
+                occurrences.iter().any(|&(field, position)| {
+                    clause.field.is_none_or(|wanted| field == wanted)
+                        && rest.iter().zip(position + 1..).all(|(term, next)| {
+                            self.postings
+                                .get(term)
+                                .and_then(|emails| emails.get(*id))
+                                .is_some_and(|occurrences| occurrences.contains(&(field, next)))
+                        })
+                })
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+/// Texts of each indexed field of an email
+fn fields(email: &Email) -> Vec<(Field, Vec<String>)> {
+    let addresses = &email.addresses;
+    let mailboxes = |list: &[EmailAddress]| -> Vec<String> {
+        list.iter()
+            .flat_map(|mailbox| [mailbox.name.clone(), mailbox.address.clone()])
+            .flatten()
+            .collect()
+    };
+
+    let mut from = vec![email.from.clone()];
+    from.extend(mailboxes(&addresses.from));
+    let mut to = email.to.clone();
+    to.extend(mailboxes(&addresses.to));
+    to.extend(mailboxes(&addresses.cc));
+    let mut body: Vec<String> = email.text_body.iter().cloned().collect();
+    body.extend(email.html_body.as_deref().map(html::to_text));
+    let attachments = email
+        .attachments
+        .iter()
+        .filter(|attachment| attachment.content_type.starts_with("text/"))
+        .filter_map(|attachment| attachment.data.as_deref())
+        .map(|data| String::from_utf8_lossy(data).into_owned())
+        .collect();
+
+    vec![
+        (Field::Subject, vec![email.subject.clone()]),
+        (Field::From, from),
+        (Field::To, to),
+        (Field::Body, body),
+        (Field::Attachment, attachments),
+    ]
+}
+
+/// Split text into lowercase words
+///
+/// Chinese and Japanese are written without spaces, so each of their characters is a word
+/// of its own. A query word in these scripts then becomes a phrase of its characters, which
+/// matches it anywhere in a sentence.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .flat_map(split_cjk)
+        .map(str::to_lowercase)
+}
+
+/// Split a word into its runs of other characters and single CJK characters
+fn split_cjk(word: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, c) in word.char_indices() {
+        if is_cjk(c) {
+            if start < i {
+                parts.push(&word[start..i]);
+            }
+            parts.push(&word[i..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    if start < word.len() {
+        parts.push(&word[start..]);
+    }
+    parts
+}
+
+/// Check whether a character belongs to a script written without spaces between words
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'       // Hiragana and Katakana
+        | '\u{31F0}'..='\u{31FF}'     // Katakana phonetic extensions
+        | '\u{3400}'..='\u{4DBF}'     // CJK unified ideographs extension A
+        | '\u{4E00}'..='\u{9FFF}'     // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}'     // CJK compatibility ideographs
+        | '\u{FF66}'..='\u{FF9F}'     // Halfwidth Katakana
+        | '\u{20000}'..='\u{2FA1F}'   // Supplementary ideographs
+    )
+}
+
+/// Split a query into clauses
+///
+/// A value that splits into several words, such as `from:alice@example.com`, is matched
+/// as a phrase.
+fn parse_query(query: &str) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+    let mut rest = query.trim_start();
+    while !rest.is_empty() {
+        let mut field = None;
+        if let Some((prefix, value)) = rest.split_once(':')
+            && !prefix.contains(|c: char| c.is_whitespace() || c == '"')
+            && let Some(prefix) = Field::from_prefix(prefix)
+        {
+            field = Some(prefix);
+            rest = value;
+        }
+
+        let text;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            text = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or_default();
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            text = &rest[..end];
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+
+        let terms: Vec<String> = tokenize(text).collect();
+        if !terms.is_empty() {
+            clauses.push(Clause { field, terms });
+        }
+    }
+    clauses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Attachment, MessageAddresses};
+
+    fn create_test_email(id: &str, subject: &str, text_body: &str) -> Email {
+        Email {
+            id: id.to_string(),
+            from: "sender@example.com".to_string(),
+            to: vec!["recipient@example.com".to_string()],
+            addresses: MessageAddresses {
+                from: vec![EmailAddress {
+                    name: Some("Alice Smith".to_string()),
+                    address: Some("alice@example.com".to_string()),
+                    group: None,
+                }],
+                ..Default::default()
+            },
+            subject: subject.to_string(),
+            text_body: Some(text_body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn ids(index: &SearchIndex, query: &str) -> Vec<String> {
+        let mut ids: Vec<String> = index.search(query).into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query(r#"Invoice  subject:"Quarterly report" from:alice@example.com other:x"#),
+            vec![
+                Clause {
+                    field: None,
+                    terms: vec!["invoice".to_string()],
+                },
+                Clause {
+                    field: Some(Field::Subject),
+                    terms: vec!["quarterly".to_string(), "report".to_string()],
+                },
+                Clause {
+                    field: Some(Field::From),
+                    terms: vec![
+                        "alice".to_string(),
+                        "example".to_string(),
+                        "com".to_string()
+                    ],
+                },
+                Clause {
+                    field: None,
+                    terms: vec!["other".to_string(), "x".to_string()],
+                },
+            ]
+        );
+        assert!(parse_query("  \"\" :: ").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let mut newsletter = create_test_email("2", "Weekly news", "");
+        newsletter.text_body = None;
+        newsletter.html_body =
+            Some("<p>The <b>quarterly report</b> is out</p><script>x</script>".to_string());
+        let mut invoice = create_test_email("3", "Your invoice", "See attached.");
+        invoice.attachments.push(Attachment {
+            id: "a".to_string(),
+            filename: "invoice.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            size: 16,
+            data: Some(b"Total: 42 EUR\n".to_vec()),
+        });
+        let index = SearchIndex::new(&[
+            create_test_email("1", "Quarterly report", "The report is late."),
+            newsletter,
+            invoice,
+        ]);
+
+        assert_eq!(ids(&index, "REPORT"), vec!["1", "2"]);
+        assert_eq!(ids(&index, "\"quarterly report\""), vec!["1", "2"]);
+        assert_eq!(ids(&index, "\"report quarterly\""), Vec::<String>::new());
+        assert_eq!(ids(&index, "subject:report"), vec!["1"]);
+        assert_eq!(ids(&index, "body:\"quarterly report\""), vec!["2"]);
+        assert_eq!(ids(&index, "report late"), vec!["1"]);
+        assert_eq!(ids(&index, "attachment:eur"), vec!["3"]);
+        assert_eq!(ids(&index, "from:\"alice smith\" invoice"), vec!["3"]);
+        assert_eq!(ids(&index, "to:recipient@example.com"), vec!["1", "2", "3"]);
+        // A phrase does not span two values of a field
+        assert_eq!(ids(&index, "from:\"com alice\""), Vec::<String>::new());
+        assert_eq!(ids(&index, ""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_search_japanese() {
+        let index = SearchIndex::new(&[
+            create_test_email(
+                "1",
+                "明日の会議について",
+                "東京本社で打ち合わせを行います。",
+            ),
+            create_test_email("2", "京都の東山", "Meeting in Kyoto"),
+        ]);
+
+        assert_eq!(
+            tokenize("Re:会議").collect::<Vec<_>>(),
+            vec!["re", "会", "議"]
+        );
+        assert_eq!(ids(&index, "会議"), vec!["1"]);
+        assert_eq!(ids(&index, "subject:会議"), vec!["1"]);
+        assert_eq!(ids(&index, "打ち合わせ"), vec!["1"]);
+        // The characters must appear in a row
+        assert_eq!(ids(&index, "東京"), vec!["1"]);
+        assert_eq!(ids(&index, "京"), vec!["1", "2"]);
+        assert_eq!(ids(&index, "大阪"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_search_after_update_and_remove() {
+        let index = SearchIndex::new(&[
+            create_test_email("1", "First", "apples"),
+            create_test_email("2", "Second", "apples and pears"),
+        ]);
+
+        index.insert(&create_test_email("1", "First", "bananas"));
+        assert_eq!(ids(&index, "apples"), vec!["2"]);
+        assert_eq!(ids(&index, "bananas"), vec!["1"]);
+
+        index.remove("2");
+        assert_eq!(ids(&index, "apples"), Vec::<String>::new());
+        assert!(!index.inner.read().unwrap().postings.contains_key("pears"));
+    }
+
+    #[test]
+    fn test_search_order() {
+        let now = Utc::now();
+        let mut emails: Vec<Email> = ["b", "c", "a", "d"]
+            .iter()
+            .map(|id| create_test_email(id, "Report", ""))
+            .collect();
+        emails[0].received_at = now;
+        emails[1].received_at = now;
+        emails[2].received_at = now;
+        emails[3].received_at = now - chrono::Duration::seconds(1);
+        let index = SearchIndex::new(&emails);
+
+        // Newest first, then by ID, on every call
+        for _ in 0..5 {
+            assert_eq!(index.search("report"), vec!["a", "b", "c", "d"]);
+        }
+    }
+}
@@ -1,17 +1,196 @@
-use chrono::Utc;
-use mail_parser::{HeaderValue, MessageParser, MimeHeaders};
-use std::collections::HashMap;
+//! SMTP server implementation for capturing emails
+
+use chrono::{DateTime, Utc};
+use mail_parser::{Addr, Address, HeaderValue, Message, MessageParser, MimeHeaders, PartType};
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
 use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::models::{AppState, Attachment, Email};
+use crate::auth::{self, AuthMode};
+use crate::models::{
+    AppState, Attachment, DEFAULT_MAILBOX, DsnRecipientRequest, DsnRequest, Email, EmailAddress,
+    EmailEvent, Header, MessageAddresses, TlsInfo,
+};
+use crate::{calendar, dsn, mime, retention, tls};
+
+/// Default maximum message size advertised through the SIZE extension (25 MiB)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
+
+/// Configuration for the SMTP listener
+#[derive(Clone)]
+pub struct SmtpConfig {
+    /// Maximum accepted message size in bytes
+    pub max_message_size: usize,
+    /// TLS acceptor used for STARTTLS and the implicit TLS listener
+    pub tls: Option<TlsAcceptor>,
+    /// Whether STARTTLS is offered on the plain listener (requires `tls`)
+    pub starttls: bool,
+    /// Port of the implicit TLS (SMTPS) listener, if enabled (requires `tls`)
+    pub smtps_port: Option<u16>,
+    /// How SMTP AUTH credentials are checked
+    pub auth: AuthMode,
+    /// Recipient-domain rules for mail sent without SMTP AUTH, checked in order
+    pub mailbox_rules: Vec<MailboxRule>,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            tls: None,
+            starttls: false,
+            smtps_port: None,
+            auth: AuthMode::default(),
+            mailbox_rules: Vec::new(),
+        }
+    }
+}
+
+/// Routes mail for a recipient domain into a named mailbox
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailboxRule {
+    /// Recipient domain, also matching its subdomains
+    pub domain: String,
+    /// Mailbox receiving the matching mail
+    pub mailbox: String,
+}
+
+impl MailboxRule {
+    /// Parse a rule of the form `domain=mailbox`
+    pub fn parse(rule: &str) -> Option<Self> {
+        let (domain, mailbox) = rule.split_once('=')?;
+        let domain = domain.trim().trim_start_matches('@').to_ascii_lowercase();
+        let mailbox = mailbox.trim();
+        if domain.is_empty() || mailbox.is_empty() {
+            return None;
+        }
+        Some(Self {
+            domain,
+            mailbox: mailbox.to_string(),
+        })
+    }
+
+    /// Check whether an address belongs to the rule's domain or one of its subdomains
+    fn matches(&self, address: &str) -> bool {
+        let Some((_, domain)) = address.rsplit_once('@') else {
+            return false;
+        };
+        let domain = domain.to_ascii_lowercase();
+        domain == self.domain
+            || domain
+                .strip_suffix(&self.domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    }
+}
+
+/// Pick the mailbox for a message
+///
+/// Mail from authenticated clients goes to the mailbox named after the AUTH username.
+/// Otherwise the first rule matching any recipient decides, falling back to the default mailbox.
+fn resolve_mailbox(auth_user: Option<&str>, rcpt_to: &[String], rules: &[MailboxRule]) -> String {
+    if let Some(user) = auth_user {
+        return user.to_string();
+    }
+    rules
+        .iter()
+        .find(|rule| rcpt_to.iter().any(|rcpt| rule.matches(rcpt)))
+        .map(|rule| rule.mailbox.clone())
+        .unwrap_or_else(|| DEFAULT_MAILBOX.to_string())
+}
+
+/// Envelope and session details recorded alongside a received message
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    /// Reverse-path given in `MAIL FROM` (empty for the null sender)
+    pub from: String,
+    /// Forward-paths given in `RCPT TO`
+    pub to: Vec<String>,
+    /// TLS parameters of the session, if the message was received over TLS
+    pub tls: Option<TlsInfo>,
+    /// Username the client authenticated as with SMTP AUTH
+    pub auth_user: Option<String>,
+    /// Mailbox the message is stored in (the default mailbox when unset)
+    pub mailbox: Option<String>,
+    /// DSN parameters of the transaction, if any were given
+    pub dsn_request: Option<DsnRequest>,
+}
+
+/// State of a single SMTP session
+#[derive(Debug, Default)]
+struct Session {
+    /// Whether the client greeted with EHLO, enabling ESMTP extensions
+    esmtp: bool,
+    /// Reverse-path of the current transaction (empty for the null sender `<>`)
+    mail_from: Option<String>,
+    /// Forward-paths of the current transaction
+    rcpt_to: Vec<String>,
+    /// Whether the current transaction was started with the SMTPUTF8 parameter
+    smtputf8: bool,
+    /// DSN parameters of the current transaction
+    dsn_request: DsnRequest,
+    /// TLS parameters, once the connection has been upgraded
+    tls: Option<TlsInfo>,
+    /// Username the client authenticated as with SMTP AUTH
+    auth_user: Option<String>,
+}
+
+/// How a pass over the SMTP command loop ended
+enum SessionEnd {
+    /// The client quit or closed the connection
+    Closed,
+    /// The client issued STARTTLS and the connection must be upgraded
+    StartTls,
+}
+
+impl Session {
+    /// Clear the current mail transaction
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+        self.smtputf8 = false;
+        self.dsn_request = DsnRequest::default();
+    }
+
+    /// Build the envelope of the current transaction
+    fn envelope(&self, config: &SmtpConfig) -> Envelope {
+        let mailbox = resolve_mailbox(
+            self.auth_user.as_deref(),
+            &self.rcpt_to,
+            &config.mailbox_rules,
+        );
+        Envelope {
+            from: self.mail_from.clone().unwrap_or_default(),
+            to: self.rcpt_to.clone(),
+            tls: self.tls.clone(),
+            auth_user: self.auth_user.clone(),
+            mailbox: Some(mailbox),
+            dsn_request: (!self.dsn_request.is_empty()).then(|| self.dsn_request.clone()),
+        }
+    }
+
+    /// Format a reply line, including the enhanced status code for ESMTP clients
+    fn reply(&self, code: u16, enhanced: &str, text: &str) -> String {
+        if self.esmtp {
+            format!("{} {} {}\r\n", code, enhanced, text)
+        } else {
+            format!("{} {}\r\n", code, text)
+        }
+    }
+}
+
+/// Greeting sent when a client connects
+const GREETING: &[u8] = b"220 MailHits SMTP Server ready\r\n";
 
-/// SMTP server implementation for capturing emails
+/// Longest command line accepted, and the most read at once while discarding message content
+const MAX_LINE_LENGTH: usize = 16 * 1024;
 
 /// Handle an individual SMTP client connection
 ///
@@ -20,121 +199,264 @@ pub async fn handle_smtp_client(
     mut stream: TcpStream,
     _addr: SocketAddr,
     state: Arc<AppState>,
+    config: Arc<SmtpConfig>,
+) -> io::Result<()> {
+    // Send greeting
+    stream.write_all(GREETING).await?;
+
+    let mut session = Session::default();
+    if let SessionEnd::StartTls = run_session(&mut stream, &mut session, &state, &config).await? {
+        // STARTTLS is only offered when an acceptor is configured
+        let Some(acceptor) = config.tls.clone() else {
+            return Ok(());
+        };
+        let mut stream = acceptor.accept(stream).await?;
+        let tls_info = tls::tls_info(stream.get_ref().1, false);
+        info!(
+            "STARTTLS negotiated {} {}",
+            tls_info.version, tls_info.cipher
+        );
+
+        // The client must start over with EHLO on the protected channel
+        session = Session {
+            tls: Some(tls_info),
+            ..Default::default()
+        };
+        run_session(&mut stream, &mut session, &state, &config).await?;
+        let _ = stream.shutdown().await;
+    }
+
+    Ok(())
+}
+
+/// Handle an individual implicit TLS (SMTPS) client connection
+///
+/// Completes the TLS handshake before the greeting, then processes SMTP commands
+/// exactly like a plain connection.
+pub async fn handle_smtps_client(
+    stream: TcpStream,
+    _addr: SocketAddr,
+    state: Arc<AppState>,
+    config: Arc<SmtpConfig>,
 ) -> io::Result<()> {
-    let (reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    let Some(acceptor) = config.tls.clone() else {
+        return Err(io::Error::other("TLS is not configured"));
+    };
+    let mut stream = acceptor.accept(stream).await?;
+    let tls_info = tls::tls_info(stream.get_ref().1, true);
+    info!("SMTPS negotiated {} {}", tls_info.version, tls_info.cipher);
 
     // Send greeting
-    writer
-        .write_all(b"220 MailHits SMTP Server ready\r\n")
-        .await?;
+    stream.write_all(GREETING).await?;
+
+    let mut session = Session {
+        tls: Some(tls_info),
+        ..Default::default()
+    };
+    run_session(&mut stream, &mut session, &state, &config).await?;
+    let _ = stream.shutdown().await;
+
+    Ok(())
+}
+
+/// Run the SMTP command loop on a plain or TLS-protected stream
+async fn run_session<S>(
+    stream: &mut S,
+    session: &mut Session,
+    state: &Arc<AppState>,
+    config: &SmtpConfig,
+) -> io::Result<SessionEnd>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut line = Vec::new();
 
     // SMTP state
-    let mut mail_from = String::new();
-    let mut rcpt_to = Vec::new();
     let mut in_data = false;
     let mut data_buffer = Vec::new();
+    let mut data_too_large = false;
+    // Whether the last read ended at a line break; overlong lines are read in pieces
+    let mut at_line_start = true;
 
     // Process commands
     loop {
+        // Read raw octets so that 8-bit message content survives untouched. Reads are capped
+        // so that a line that never ends cannot exhaust memory: message content only up to
+        // just past the size limit (but always a whole terminator line), commands only up to
+        // the maximum line length.
+        let limit = if in_data && !data_too_large {
+            config
+                .max_message_size
+                .saturating_add(1)
+                .saturating_sub(data_buffer.len())
+                .max(3)
+        } else {
+            MAX_LINE_LENGTH
+        };
         line.clear();
-        if reader.read_line(&mut line).await? == 0 {
-            break; // Connection closed
+        if (&mut stream)
+            .take(limit as u64)
+            .read_until(b'\n', &mut line)
+            .await?
+            == 0
+        {
+            // Connection closed; an unterminated message is an aborted transaction
+            if in_data {
+                warn!("Connection closed during DATA, discarding the incomplete message");
+            }
+            break;
         }
-
-        info!("SMTP << {}", line.trim());
+        let line_start = at_line_start;
+        at_line_start = line.len() < limit || line.ends_with(b"\n");
 
         if in_data {
             // In DATA mode, collect lines until we see a lone "."
-            if line.trim() == "." {
+            if line_start && is_data_terminator(&line) {
                 in_data = false;
 
-                // Process the collected email data
-                if let Err(e) = process_email(
-                    &data_buffer,
-                    mail_from.clone(),
-                    rcpt_to.clone(),
-                    state.clone(),
-                )
-                .await
+                if data_too_large {
+                    stream
+                        .write_all(
+                            session
+                                .reply(552, "5.3.4", "Message size exceeds fixed maximum")
+                                .as_bytes(),
+                        )
+                        .await?;
+                } else if let Err(e) =
+                    process_email(&data_buffer, session.envelope(config), state.clone()).await
                 {
+                    // Process the collected email data
                     warn!("Failed to process email: {}", e);
-                    writer.write_all(b"554 Transaction failed\r\n").await?;
+                    stream
+                        .write_all(session.reply(554, "5.6.0", "Transaction failed").as_bytes())
+                        .await?;
                 } else {
-                    writer.write_all(b"250 OK: Message accepted\r\n").await?;
+                    stream
+                        .write_all(
+                            session
+                                .reply(250, "2.0.0", "OK: Message accepted")
+                                .as_bytes(),
+                        )
+                        .await?;
                 }
 
                 // Reset state for next email
-                mail_from.clear();
-                rcpt_to.clear();
+                session.reset();
                 data_buffer.clear();
-            } else {
-                // Collect the data line (removing the leading dot if line starts with ..)
-                let line_content = if line.starts_with("..") {
-                    line[1..].to_string()
+                data_too_large = false;
+            } else if !data_too_large {
+                // Collect the data line (removing the leading dot if the line was dot-stuffed)
+                let data = if line_start {
+                    unstuff_data_line(&line)
                 } else {
-                    line.clone()
+                    &line
                 };
-                data_buffer.extend_from_slice(line_content.as_bytes());
+                data_buffer.extend_from_slice(data);
+                if data_buffer.len() > config.max_message_size {
+                    // Keep reading until the terminator, but stop buffering
+                    data_too_large = true;
+                    data_buffer.clear();
+                }
             }
             continue;
         }
 
+        if !line_start {
+            continue; // Rest of an overlong command line, already rejected
+        }
+        if !at_line_start {
+            stream
+                .write_all(session.reply(500, "5.5.2", "Line too long").as_bytes())
+                .await?;
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        info!("SMTP << {}", line.trim());
+
         // Parse command
         let parts: Vec<&str> = line.trim().splitn(2, ' ').collect();
         let command = parts[0].to_uppercase();
 
         match command.as_str() {
-            "HELO" | "EHLO" => {
+            "HELO" => {
                 let domain = if parts.len() > 1 { parts[1] } else { "unknown" };
                 info!("HELO from {}", domain);
-                writer.write_all(b"250 MailHits\r\n").await?;
+                session.esmtp = false;
+                session.reset();
+                stream.write_all(b"250 MailHits\r\n").await?;
+            }
+            "EHLO" => {
+                let domain = if parts.len() > 1 { parts[1] } else { "unknown" };
+                info!("EHLO from {}", domain);
+                session.esmtp = true;
+                session.reset();
+                stream
+                    .write_all(ehlo_response(domain, config, session).as_bytes())
+                    .await?;
             }
             "MAIL" => {
-                if let Some(from_part) = parts.get(1) {
-                    if let Some(from) = from_part.strip_prefix("FROM:") {
-                        mail_from = from.trim().to_string();
-                        mail_from.retain(|c| c != '<' && c != '>');
-                        info!("MAIL FROM: {}", mail_from);
-                        writer.write_all(b"250 OK\r\n").await?;
-                    } else {
-                        writer
-                            .write_all(b"501 Syntax error in parameters\r\n")
-                            .await?;
+                let reply = match parts
+                    .get(1)
+                    .and_then(|arg| parse_path_argument(arg, "FROM:"))
+                {
+                    None => session.reply(501, "5.5.4", "Syntax error in parameters"),
+                    Some(_) if session.mail_from.is_some() => {
+                        session.reply(503, "5.5.1", "Sender already specified")
                     }
-                } else {
-                    writer
-                        .write_all(b"501 Syntax error in parameters\r\n")
-                        .await?;
-                }
+                    Some((path, params)) => match check_mail_parameters(&params, config) {
+                        Err(reply) => session.reply(reply.0, reply.1, reply.2),
+                        Ok(params) if !params.smtputf8 && !path.is_ascii() => {
+                            session.reply(553, "5.6.7", "Non-ASCII address requires SMTPUTF8")
+                        }
+                        Ok(params) => {
+                            info!("MAIL FROM: {}", path);
+                            session.mail_from = Some(path);
+                            session.smtputf8 = params.smtputf8;
+                            session.dsn_request.ret = params.ret;
+                            session.dsn_request.envid = params.envid;
+                            session.reply(250, "2.1.0", "OK")
+                        }
+                    },
+                };
+                stream.write_all(reply.as_bytes()).await?;
             }
             "RCPT" => {
-                if let Some(to_part) = parts.get(1) {
-                    if let Some(to) = to_part.strip_prefix("TO:") {
-                        let mut to = to.trim().to_string();
-                        to.retain(|c| c != '<' && c != '>');
-                        rcpt_to.push(to.clone());
-                        info!("RCPT TO: {}", to);
-                        writer.write_all(b"250 OK\r\n").await?;
-                    } else {
-                        writer
-                            .write_all(b"501 Syntax error in parameters\r\n")
-                            .await?;
+                let reply = match parts.get(1).and_then(|arg| parse_path_argument(arg, "TO:")) {
+                    None => session.reply(501, "5.5.4", "Syntax error in parameters"),
+                    Some((path, _)) if path.is_empty() => {
+                        session.reply(501, "5.1.3", "Syntax error in mailbox address")
                     }
-                } else {
-                    writer
-                        .write_all(b"501 Syntax error in parameters\r\n")
-                        .await?;
-                }
+                    Some(_) if session.mail_from.is_none() => {
+                        session.reply(503, "5.5.1", "Bad sequence of commands")
+                    }
+                    Some((path, params)) => match check_rcpt_parameters(&path, &params) {
+                        Err(reply) => session.reply(reply.0, reply.1, reply.2),
+                        Ok(_) if !session.smtputf8 && !path.is_ascii() => {
+                            session.reply(553, "5.6.7", "Non-ASCII address requires SMTPUTF8")
+                        }
+                        Ok(dsn) => {
+                            info!("RCPT TO: {}", path);
+                            session.dsn_request.recipients.extend(dsn);
+                            session.rcpt_to.push(path);
+                            session.reply(250, "2.1.5", "OK")
+                        }
+                    },
+                };
+                stream.write_all(reply.as_bytes()).await?;
             }
             "DATA" => {
-                if mail_from.is_empty() || rcpt_to.is_empty() {
-                    writer
-                        .write_all(b"503 Bad sequence of commands\r\n")
+                if session.mail_from.is_none() || session.rcpt_to.is_empty() {
+                    stream
+                        .write_all(
+                            session
+                                .reply(503, "5.5.1", "Bad sequence of commands")
+                                .as_bytes(),
+                        )
                         .await?;
                 } else {
-                    writer
+                    stream
                         .write_all(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n")
                         .await?;
                     in_data = true;
@@ -142,92 +464,485 @@ pub async fn handle_smtp_client(
                 }
             }
             "RSET" => {
-                mail_from.clear();
-                rcpt_to.clear();
+                session.reset();
                 data_buffer.clear();
                 in_data = false;
-                writer.write_all(b"250 OK\r\n").await?;
+                stream
+                    .write_all(session.reply(250, "2.0.0", "OK").as_bytes())
+                    .await?;
             }
             "NOOP" => {
-                writer.write_all(b"250 OK\r\n").await?;
+                stream
+                    .write_all(session.reply(250, "2.0.0", "OK").as_bytes())
+                    .await?;
+            }
+            "AUTH" => {
+                let reply = if session.auth_user.is_some() {
+                    session.reply(503, "5.5.1", "Already authenticated")
+                } else if session.mail_from.is_some() {
+                    session.reply(503, "5.5.1", "AUTH not permitted during a mail transaction")
+                } else {
+                    let arg = parts.get(1).copied().unwrap_or_default();
+                    match authenticate(&mut stream, arg, session, config).await? {
+                        Ok(username) => {
+                            info!("AUTH as {}", username);
+                            session.auth_user = Some(username);
+                            session.reply(235, "2.7.0", "Authentication successful")
+                        }
+                        Err(reply) => reply,
+                    }
+                };
+                stream.write_all(reply.as_bytes()).await?;
+            }
+            "STARTTLS" => {
+                let reply = if config.tls.is_none() || !config.starttls {
+                    session.reply(502, "5.5.1", "Command not implemented")
+                } else if session.tls.is_some() {
+                    session.reply(503, "5.5.1", "TLS already active")
+                } else if parts.len() > 1 {
+                    session.reply(501, "5.5.4", "Syntax error in parameters")
+                } else {
+                    stream
+                        .write_all(session.reply(220, "2.0.0", "Ready to start TLS").as_bytes())
+                        .await?;
+                    // Anything pipelined after STARTTLS is discarded with the buffer
+                    return Ok(SessionEnd::StartTls);
+                };
+                stream.write_all(reply.as_bytes()).await?;
             }
             "QUIT" => {
-                writer
-                    .write_all(b"221 MailHits closing connection\r\n")
+                stream
+                    .write_all(
+                        session
+                            .reply(221, "2.0.0", "MailHits closing connection")
+                            .as_bytes(),
+                    )
                     .await?;
-                break;
+                return Ok(SessionEnd::Closed);
             }
             _ => {
-                writer.write_all(b"500 Command not recognized\r\n").await?;
+                stream
+                    .write_all(
+                        session
+                            .reply(500, "5.5.1", "Command not recognized")
+                            .as_bytes(),
+                    )
+                    .await?;
             }
         }
     }
 
-    Ok(())
+    Ok(SessionEnd::Closed)
+}
+
+/// Build the multi-line EHLO response listing the supported ESMTP extensions
+fn ehlo_response(domain: &str, config: &SmtpConfig, session: &Session) -> String {
+    let mut lines = vec![
+        format!("MailHits greets {}", domain),
+        format!("SIZE {}", config.max_message_size),
+        "8BITMIME".to_string(),
+        "PIPELINING".to_string(),
+        "ENHANCEDSTATUSCODES".to_string(),
+        "DSN".to_string(),
+        format!("AUTH {}", auth::MECHANISMS),
+        "SMTPUTF8".to_string(),
+    ];
+    if config.tls.is_some() && config.starttls && session.tls.is_none() {
+        lines.push("STARTTLS".to_string());
+    }
+
+    let last = lines.len() - 1;
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| format!("250{}{}\r\n", if i == last { ' ' } else { '-' }, line))
+        .collect()
+}
+
+/// Run an AUTH exchange
+///
+/// Returns the authenticated username, or the reply to send when authentication failed.
+async fn authenticate<S>(
+    stream: &mut S,
+    arg: &str,
+    session: &Session,
+    config: &SmtpConfig,
+) -> io::Result<Result<String, String>>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let (mechanism, initial_response) = match arg.split_once(' ') {
+        Some((mechanism, response)) => (mechanism, Some(response.trim().to_string())),
+        None => (arg, None),
+    };
+    let malformed = session.reply(501, "5.5.2", "Cannot decode response");
+    let invalid = session.reply(535, "5.7.8", "Authentication credentials invalid");
+
+    match mechanism.to_ascii_uppercase().as_str() {
+        "PLAIN" => {
+            let response = match initial_response {
+                Some(response) => response,
+                None => match auth_challenge(stream, session, "").await? {
+                    Ok(response) => response,
+                    Err(reply) => return Ok(Err(reply)),
+                },
+            };
+            let Some((username, password)) = auth::parse_plain(&response) else {
+                return Ok(Err(malformed));
+            };
+            if config.auth.check_password(&username, &password) {
+                Ok(Ok(username))
+            } else {
+                Ok(Err(invalid))
+            }
+        }
+        "LOGIN" => {
+            let username = match initial_response {
+                Some(response) => response,
+                None => match auth_challenge(stream, session, "Username:").await? {
+                    Ok(response) => response,
+                    Err(reply) => return Ok(Err(reply)),
+                },
+            };
+            let Some(username) = auth::decode_text(&username) else {
+                return Ok(Err(malformed));
+            };
+            let password = match auth_challenge(stream, session, "Password:").await? {
+                Ok(response) => response,
+                Err(reply) => return Ok(Err(reply)),
+            };
+            let Some(password) = auth::decode_text(&password) else {
+                return Ok(Err(malformed));
+            };
+            if config.auth.check_password(&username, &password) {
+                Ok(Ok(username))
+            } else {
+                Ok(Err(invalid))
+            }
+        }
+        "CRAM-MD5" if initial_response.is_none() => {
+            let challenge = auth::cram_md5_challenge();
+            let response = match auth_challenge(stream, session, &challenge).await? {
+                Ok(response) => response,
+                Err(reply) => return Ok(Err(reply)),
+            };
+            let Some((username, digest)) = auth::parse_cram_md5(&response) else {
+                return Ok(Err(malformed));
+            };
+            if config.auth.check_cram_md5(&username, &challenge, &digest) {
+                Ok(Ok(username))
+            } else {
+                Ok(Err(invalid))
+            }
+        }
+        "CRAM-MD5" => Ok(Err(session.reply(
+            501,
+            "5.5.4",
+            "Initial response not allowed",
+        ))),
+        _ => Ok(Err(session.reply(
+            504,
+            "5.5.4",
+            "Unrecognized authentication type",
+        ))),
+    }
+}
+
+/// Send a `334` challenge and read the client's response line
+///
+/// Returns the reply to send instead when the client cancels the exchange with `*` or
+/// sends a line longer than [`MAX_LINE_LENGTH`].
+async fn auth_challenge<S>(
+    stream: &mut S,
+    session: &Session,
+    challenge: &str,
+) -> io::Result<Result<String, String>>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(format!("334 {}\r\n", auth::encode(challenge)).as_bytes())
+        .await?;
+
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LENGTH as u64;
+    if (&mut *stream)
+        .take(limit)
+        .read_until(b'\n', &mut line)
+        .await?
+        == 0
+    {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if line.len() == MAX_LINE_LENGTH && !line.ends_with(b"\n") {
+        // Skip the rest of the line, so that it is not taken for a command
+        let mut rest = Vec::new();
+        while (&mut *stream)
+            .take(limit)
+            .read_until(b'\n', &mut rest)
+            .await?
+            > 0
+            && !rest.ends_with(b"\n")
+        {
+            rest.clear();
+        }
+        return Ok(Err(session.reply(501, "5.5.2", "Line too long")));
+    }
+
+    // Anything but base64 fails to decode, so invalid UTF-8 is left to the caller to reject
+    let line = String::from_utf8_lossy(&line);
+    let response = line.trim();
+    if response == "*" {
+        return Ok(Err(session.reply(501, "5.7.0", "Authentication cancelled")));
+    }
+    Ok(Ok(response.to_string()))
+}
+
+/// ESMTP parameters given after a path, as upper-cased keywords with optional values
+type EsmtpParams = Vec<(String, Option<String>)>;
+
+/// Parse the argument of `MAIL FROM:` or `RCPT TO:` into a path and its ESMTP parameters
+///
+/// The prefix is matched case-insensitively. Returns `None` on syntax errors.
+fn parse_path_argument(arg: &str, prefix: &str) -> Option<(String, EsmtpParams)> {
+    if !arg.get(..prefix.len())?.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = arg[prefix.len()..].trim_start();
+
+    let (path, params) = if let Some(bracketed) = rest.strip_prefix('<') {
+        let end = bracketed.find('>')?;
+        (&bracketed[..end], &bracketed[end + 1..])
+    } else {
+        // Be lenient with clients that omit the angle brackets
+        let (path, params) = rest.split_once(' ').unwrap_or((rest, ""));
+        if path.is_empty() {
+            return None;
+        }
+        (path, params)
+    };
+    if !params.is_empty() && !params.starts_with(' ') {
+        return None;
+    }
+
+    let params = params
+        .split_whitespace()
+        .map(|param| match param.split_once('=') {
+            Some((key, value)) => (key.to_ascii_uppercase(), Some(value.to_string())),
+            None => (param.to_ascii_uppercase(), None),
+        })
+        .collect();
+
+    Some((path.to_string(), params))
+}
+
+/// Reply (code, enhanced code, text) rejecting a command
+type Reply = (u16, &'static str, &'static str);
+
+/// Maximum length of the `ENVID` parameter (RFC 3461 section 4.4)
+const MAX_ENVID_LENGTH: usize = 100;
+
+/// Accepted ESMTP parameters of a `MAIL FROM:` command
+#[derive(Debug, Default)]
+struct MailParams {
+    /// Whether SMTPUTF8 was requested
+    smtputf8: bool,
+    /// DSN `RET` parameter, upper-cased
+    ret: Option<String>,
+    /// DSN `ENVID` parameter, decoded from xtext
+    envid: Option<String>,
+}
+
+/// Validate the ESMTP parameters of a `MAIL FROM:` command
+///
+/// Returns the accepted parameters, or the reply to reject the command with.
+fn check_mail_parameters(params: &EsmtpParams, config: &SmtpConfig) -> Result<MailParams, Reply> {
+    let mut accepted = MailParams::default();
+    for (key, value) in params {
+        match (key.as_str(), value.as_deref()) {
+            ("SIZE", Some(size)) => {
+                let size: usize = size
+                    .parse()
+                    .map_err(|_| (501, "5.5.4", "Invalid SIZE parameter"))?;
+                if size > config.max_message_size {
+                    return Err((552, "5.3.4", "Message size exceeds fixed maximum"));
+                }
+            }
+            ("BODY", Some(body))
+                if body.eq_ignore_ascii_case("7BIT") || body.eq_ignore_ascii_case("8BITMIME") => {}
+            ("BODY", _) => return Err((501, "5.5.4", "Unsupported BODY type")),
+            ("SMTPUTF8", None) => accepted.smtputf8 = true,
+            ("RET", Some(ret))
+                if accepted.ret.is_none()
+                    && (ret.eq_ignore_ascii_case("FULL") || ret.eq_ignore_ascii_case("HDRS")) =>
+            {
+                accepted.ret = Some(ret.to_ascii_uppercase());
+            }
+            ("RET", _) => return Err((501, "5.5.4", "Invalid RET parameter")),
+            ("ENVID", Some(envid))
+                if accepted.envid.is_none() && envid.len() <= MAX_ENVID_LENGTH =>
+            {
+                let envid = decode_xtext(envid).ok_or((501, "5.5.4", "Invalid ENVID parameter"))?;
+                accepted.envid = Some(envid);
+            }
+            ("ENVID", _) => return Err((501, "5.5.4", "Invalid ENVID parameter")),
+            _ => return Err((555, "5.5.4", "Unsupported parameter")),
+        }
+    }
+    Ok(accepted)
+}
+
+/// Validate the ESMTP parameters of a `RCPT TO:` command
+///
+/// Returns the DSN parameters of the recipient, if any, or the reply to reject the
+/// command with.
+fn check_rcpt_parameters(
+    path: &str,
+    params: &EsmtpParams,
+) -> Result<Option<DsnRecipientRequest>, Reply> {
+    let mut notify = None;
+    let mut orcpt = None;
+    for (key, value) in params {
+        match (key.as_str(), value.as_deref()) {
+            ("NOTIFY", Some(value)) if notify.is_none() => {
+                let conditions =
+                    parse_notify(value).ok_or((501, "5.5.4", "Invalid NOTIFY parameter"))?;
+                notify = Some(conditions);
+            }
+            ("NOTIFY", _) => return Err((501, "5.5.4", "Invalid NOTIFY parameter")),
+            ("ORCPT", Some(value)) if orcpt.is_none() => {
+                let (addr_type, address) = value
+                    .split_once(';')
+                    .filter(|(addr_type, _)| !addr_type.is_empty())
+                    .and_then(|(addr_type, address)| Some((addr_type, decode_xtext(address)?)))
+                    .ok_or((501, "5.5.4", "Invalid ORCPT parameter"))?;
+                orcpt = Some(format!("{};{}", addr_type, address));
+            }
+            ("ORCPT", _) => return Err((501, "5.5.4", "Invalid ORCPT parameter")),
+            _ => return Err((555, "5.5.4", "Unsupported parameter")),
+        }
+    }
+
+    if notify.is_none() && orcpt.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(DsnRecipientRequest {
+        address: path.to_string(),
+        notify: notify.unwrap_or_default(),
+        orcpt,
+    }))
+}
+
+/// Parse the value of a `NOTIFY` parameter into upper-cased conditions
+///
+/// `NEVER` must stand alone; otherwise any of `SUCCESS`, `FAILURE` and `DELAY` are allowed.
+fn parse_notify(value: &str) -> Option<Vec<String>> {
+    let conditions: Vec<String> = value.split(',').map(str::to_ascii_uppercase).collect();
+    let valid = if conditions.iter().any(|c| c == "NEVER") {
+        conditions.len() == 1
+    } else {
+        conditions
+            .iter()
+            .all(|c| matches!(c.as_str(), "SUCCESS" | "FAILURE" | "DELAY"))
+    };
+    valid.then_some(conditions)
+}
+
+/// Decode an xtext value (RFC 3461 section 4), in which `+XX` encodes a byte in hex
+fn decode_xtext(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'+' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'!'..=b'~' if byte != b'=' => bytes.push(byte),
+            _ => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Check whether a raw DATA line is the end-of-data marker
+///
+/// Accepts both `.<CRLF>` and a bare `.<LF>` for lenient clients. A dot without a line
+/// break, such as at the end of a dropped connection, does not end the message.
+fn is_data_terminator(line: &[u8]) -> bool {
+    line == b".\r\n" || line == b".\n"
+}
+
+/// Remove the transparency dot from a raw DATA line (RFC 5321 section 4.5.2)
+///
+/// Every other octet of the line, including bare CR or LF characters, is kept as-is.
+fn unstuff_data_line(line: &[u8]) -> &[u8] {
+    match line.strip_prefix(b".") {
+        Some(rest) if !rest.is_empty() => rest,
+        _ => line,
+    }
 }
 
 /// Process an email received via SMTP
 ///
 /// Parses the raw email data, extracts headers, body parts, and attachments,
 /// then stores the email in the application state and broadcasts it to WebSocket clients
-async fn process_email(
-    data: &[u8],
-    from: String,
-    to: Vec<String>,
-    state: Arc<AppState>,
-) -> io::Result<()> {
-    // Try to parse the email data
-    let email_str = String::from_utf8_lossy(data);
-
+async fn process_email(data: &[u8], envelope: Envelope, state: Arc<AppState>) -> io::Result<()> {
     // Try to parse with mail-parser
     let parsed = match MessageParser::default().parse(data) {
         Some(parsed) => parsed,
         None => {
             // Fallback to simple parsing
-            return process_email_simple(&email_str, from, to, state).await;
+            return process_email_simple(data, envelope, state).await;
         }
     };
 
-    // Extract headers - simplified approach
+    let mailbox = envelope
+        .mailbox
+        .unwrap_or_else(|| DEFAULT_MAILBOX.to_string());
+    let mut email = parse_message(&parsed, Uuid::new_v4().to_string(), Utc::now(), &mailbox);
+
+    // Add the details of the SMTP session
+    email.bcc = hidden_recipients(&envelope.to, &email.addresses);
+    email.from = envelope.from;
+    email.to = envelope.to;
+    email.tls = envelope.tls;
+    email.auth_user = envelope.auth_user;
+    email.dsn_request = envelope.dsn_request;
+
+    store_email(&state, email, data)
+}
+
+/// Build an email from a parsed message, leaving out the SMTP envelope and session
+///
+/// Attached `message/rfc822` parts are parsed recursively into child emails.
+fn parse_message(parsed: &Message, id: String, received_at: DateTime<Utc>, mailbox: &str) -> Email {
+    // Extract headers in message order
     let mut headers = HashMap::new();
+    let mut header_list = Vec::new();
     for header in parsed.headers() {
         let name = header.name().to_string();
-        // Convert header value to string - just use a simple representation
-        let value = match header.value() {
-            HeaderValue::Address(address) => address
-                .iter()
-                .map(|addr| {
-                    format!(
-                        "{}&lt;{}&gt;",
-                        addr.clone().name.unwrap_or_default().to_string(),
-                        addr.clone().address.unwrap().to_string()
-                    )
-                    .trim()
-                    .to_string()
-                })
-                .collect::<Vec<String>>()
-                .join(", "),
-            HeaderValue::Text(text) => text.to_string(),
-            HeaderValue::ContentType(content_type) => format!(
-                "{}/{}; {}",
-                content_type.clone().c_type,
-                content_type.clone().c_subtype.unwrap_or_default(),
-                content_type
-                    .clone()
-                    .attributes
-                    .unwrap_or_default()
-                    .iter()
-                    .map(|attr| format!("{}={}", attr.name, attr.value))
-                    .collect::<Vec<String>>()
-                    .join("; ")
-            ),
-            HeaderValue::DateTime(date_time) => date_time.to_string(),
-            _ => format!("{:?}", header.value()),
+        let field = |start: u32, end: u32| {
+            String::from_utf8_lossy(mime::raw_slice(parsed, start, end)).to_string()
         };
-        // let value = format!("{:?}", header.value());
+        let raw = field(header.offset_start, header.offset_end)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        let value = header_value_to_string(header.value()).unwrap_or_else(|| unfold(&raw));
+
+        header_list.push(Header {
+            name: field(header.offset_field, header.offset_start)
+                .trim_end_matches(':')
+                .to_string(),
+            raw,
+            value: value.clone(),
+        });
         headers.insert(name, value);
     }
 
+    // Extract address headers
+    let addresses = message_addresses(parsed);
+
     // Extract subject
     let subject = parsed.subject().unwrap_or("No Subject").to_string();
 
@@ -236,22 +951,26 @@ async fn process_email(
     let mut html_body = None;
 
     // Get text body from parts
-    if parsed.text_body_count() > 0 {
-        if let Some(part) = parsed.text_bodies().next() {
-            text_body = Some(part.to_string());
-        }
+    if parsed.text_body_count() > 0
+        && let Some(part) = parsed.text_bodies().next()
+    {
+        text_body = Some(part.to_string());
     }
 
     // Get HTML body from parts
-    if parsed.html_body_count() > 0 {
-        if let Some(part) = parsed.html_bodies().next() {
-            html_body = Some(part.to_string());
-        }
+    if parsed.html_body_count() > 0
+        && let Some(part) = parsed.html_bodies().next()
+    {
+        html_body = Some(part.to_string());
     }
 
     // Extract attachments
     let mut attachments = Vec::new();
     for attachment in parsed.attachments() {
+        // Attached messages are parsed into child emails instead
+        if let PartType::Message(_) = attachment.body {
+            continue;
+        }
         let id = Uuid::new_v4().to_string();
         let filename = attachment
             .attachment_name()
@@ -281,29 +1000,199 @@ async fn process_email(
         });
     }
 
-    // Create email object
-    let email = Email {
-        id: Uuid::new_v4().to_string(),
-        received_at: Utc::now(),
-        from,
-        to,
+    // Parse attached messages
+    let attached_messages = mime::attached_messages(parsed)
+        .enumerate()
+        .map(|(i, nested)| {
+            let mut child =
+                parse_message(nested, format!("{}.{}", id, i + 1), received_at, mailbox);
+            child.parent_id = Some(id.clone());
+            child
+        })
+        .collect();
+
+    Email {
+        id,
+        received_at,
+        from: String::new(),
+        to: Vec::new(),
+        addresses,
+        bcc: Vec::new(),
         subject,
         text_body,
         html_body,
         headers,
+        header_list,
+        message_id: parsed.message_id().map(str::to_string),
+        in_reply_to: message_ids(parsed.in_reply_to()),
+        references: message_ids(parsed.references()),
+        thread_id: String::new(),
         attachments,
+        mime: Some(mime::mime_tree(parsed)),
+        attached_messages,
+        parent_id: None,
+        dsn: dsn::parse_dsn(parsed),
+        calendar: calendar::parse_calendar(parsed),
+        tls: None,
+        auth_user: None,
+        dsn_request: None,
+        mailbox: mailbox.to_string(),
+        size: parsed.raw_message().len(),
+    }
+}
+
+/// Convert a parsed header value to its display string
+///
+/// Returns `None` for header types without a textual representation, such as `Received`.
+fn header_value_to_string(value: &HeaderValue) -> Option<String> {
+    let value = match value {
+        HeaderValue::Address(Address::List(list)) => list
+            .iter()
+            .map(format_address)
+            .collect::<Vec<String>>()
+            .join(", "),
+        HeaderValue::Address(Address::Group(groups)) => groups
+            .iter()
+            .map(|group| {
+                let members = group
+                    .addresses
+                    .iter()
+                    .map(format_address)
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                match &group.name {
+                    Some(name) => format!("{}: {};", name, members),
+                    None => members,
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+        HeaderValue::Text(text) => text.to_string(),
+        HeaderValue::TextList(list) => list.join(", "),
+        HeaderValue::ContentType(content_type) => format!(
+            "{}/{}; {}",
+            content_type.clone().c_type,
+            content_type.clone().c_subtype.unwrap_or_default(),
+            content_type
+                .clone()
+                .attributes
+                .unwrap_or_default()
+                .iter()
+                .map(|attr| format!("{}={}", attr.name, attr.value))
+                .collect::<Vec<String>>()
+                .join("; ")
+        ),
+        HeaderValue::DateTime(date_time) => date_time.to_string(),
+        _ => return None,
     };
+    Some(value)
+}
 
-    // Store email
-    {
-        let mut emails = state.emails.write().unwrap();
-        emails.push(email.clone());
+/// Format a single mailbox as `Name <address>`
+fn format_address(addr: &Addr) -> String {
+    match (&addr.name, &addr.address) {
+        (Some(name), Some(address)) => format!("{} <{}>", name, address),
+        (None, Some(address)) => address.to_string(),
+        (Some(name), None) => name.to_string(),
+        (None, None) => String::new(),
     }
+}
 
-    // Broadcast to websocket clients
-    let _ = state.tx.send(email);
+/// Convert the address headers of a parsed message
+fn message_addresses(parsed: &Message) -> MessageAddresses {
+    MessageAddresses {
+        from: parse_addresses(parsed.from()),
+        sender: parse_addresses(parsed.sender()),
+        reply_to: parse_addresses(parsed.reply_to()),
+        to: parse_addresses(parsed.to()),
+        cc: parse_addresses(parsed.cc()),
+        bcc: parse_addresses(parsed.bcc()),
+    }
+}
 
-    Ok(())
+/// Names of the header fields holding addresses
+const ADDRESS_HEADERS: [&str; 6] = ["From", "Sender", "Reply-To", "To", "Cc", "Bcc"];
+
+/// Parse the address headers collected by the simple parser on their own
+fn simple_addresses(header_list: &[Header]) -> MessageAddresses {
+    let mut fields: String = header_list
+        .iter()
+        .filter(|header| {
+            ADDRESS_HEADERS
+                .iter()
+                .any(|name| header.name.eq_ignore_ascii_case(name))
+        })
+        .map(|header| format!("{}:{}\r\n", header.name, header.raw))
+        .collect();
+    fields.push_str("\r\n");
+
+    MessageParser::default()
+        .parse_headers(&fields)
+        .map(|parsed| message_addresses(&parsed))
+        .unwrap_or_default()
+}
+
+/// Convert a parsed address header, keeping RFC 5322 group names
+fn parse_addresses(address: Option<&Address>) -> Vec<EmailAddress> {
+    let entry = |addr: &Addr, group: Option<&str>| EmailAddress {
+        name: addr.name.as_deref().map(str::to_string),
+        address: addr.address.as_deref().map(str::to_string),
+        group: group.map(str::to_string),
+    };
+
+    match address {
+        None => Vec::new(),
+        Some(Address::List(list)) => list.iter().map(|addr| entry(addr, None)).collect(),
+        Some(Address::Group(groups)) => groups
+            .iter()
+            .flat_map(|group| {
+                let name = group.name.as_deref();
+                if group.addresses.is_empty() {
+                    // Keep empty groups such as `undisclosed-recipients:;`
+                    name.map(|name| EmailAddress {
+                        group: Some(name.to_string()),
+                        ..Default::default()
+                    })
+                    .into_iter()
+                    .collect()
+                } else {
+                    group
+                        .addresses
+                        .iter()
+                        .map(|addr| entry(addr, name))
+                        .collect::<Vec<_>>()
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Find the envelope recipients that are not visible in the `To` or `Cc` header
+///
+/// Addresses are compared case-insensitively.
+fn hidden_recipients(envelope_to: &[String], addresses: &MessageAddresses) -> Vec<String> {
+    let visible: HashSet<String> = addresses
+        .to
+        .iter()
+        .chain(&addresses.cc)
+        .filter_map(|entry| entry.address.as_deref())
+        .map(str::to_lowercase)
+        .collect();
+
+    envelope_to
+        .iter()
+        .filter(|rcpt| !visible.contains(&rcpt.to_lowercase()))
+        .cloned()
+        .collect()
+}
+
+/// Unfold a raw header value into a single line
+fn unfold(raw: &str) -> String {
+    raw.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 /// Process an email using a simple parser when the main parser fails
@@ -311,40 +1200,37 @@ async fn process_email(
 /// Fallback method for parsing emails that can't be parsed by the mail-parser library.
 /// Uses a simpler line-by-line approach to extract headers and body.
 async fn process_email_simple(
-    email_str: &str,
-    from: String,
-    to: Vec<String>,
+    data: &[u8],
+    envelope: Envelope,
     state: Arc<AppState>,
 ) -> io::Result<()> {
+    let email_str = String::from_utf8_lossy(data);
+
     // Extract headers and body
     let mut headers = HashMap::new();
+    let mut header_list = Vec::new();
     let mut body_parts = Vec::new();
 
-    let mut lines = email_str.lines();
+    let lines = email_str.lines();
     let mut in_headers = true;
     let mut current_header = String::new();
 
     // Simple parser for email format
-    while let Some(line) = lines.next() {
+    for line in lines {
         if in_headers {
             if line.is_empty() {
                 in_headers = false;
+                push_simple_header(&current_header, &mut headers, &mut header_list);
                 continue;
             }
 
             if line.starts_with(' ') || line.starts_with('\t') {
                 // Continuation of previous header
-                current_header.push(' ');
-                current_header.push_str(line.trim());
+                current_header.push_str("\r\n");
+                current_header.push_str(line);
             } else {
                 // New header
-                if !current_header.is_empty() {
-                    if let Some(colon_pos) = current_header.find(':') {
-                        let key = current_header[..colon_pos].trim().to_string();
-                        let value = current_header[colon_pos + 1..].trim().to_string();
-                        headers.insert(key, value);
-                    }
-                }
+                push_simple_header(&current_header, &mut headers, &mut header_list);
                 current_header = line.to_string();
             }
         } else {
@@ -353,14 +1239,10 @@ async fn process_email_simple(
         }
     }
 
-    // // Process the last header if any
-    // if !current_header.is_empty() && in_headers {
-    //     if let Some(colon_pos) = current_header.find(':') {
-    //         let key = current_header[..colon_pos].trim().to_string();
-    //         let value = current_header[colon_pos + 1..].trim().to_string();
-    //         headers.insert(key, value);
-    //     }
-    // }
+    // Process the last header of a message without body
+    if in_headers {
+        push_simple_header(&current_header, &mut headers, &mut header_list);
+    }
 
     // Extract subject
     let subject = headers
@@ -382,66 +1264,150 @@ async fn process_email_simple(
         (Some(body), None)
     };
 
+    // Hidden recipients do not depend on which parser read the message
+    let addresses = simple_addresses(&header_list);
+    let bcc = hidden_recipients(&envelope.to, &addresses);
+
     // Create email object
     let email = Email {
         id: Uuid::new_v4().to_string(),
         received_at: Utc::now(),
-        from,
-        to,
+        from: envelope.from,
+        to: envelope.to,
+        addresses,
+        bcc,
         subject,
         text_body,
         html_body,
         headers,
-        attachments: Vec::new(), // Simple implementation without attachment parsing
+        header_list,
+        tls: envelope.tls,
+        auth_user: envelope.auth_user,
+        dsn_request: envelope.dsn_request,
+        mailbox: envelope
+            .mailbox
+            .unwrap_or_else(|| DEFAULT_MAILBOX.to_string()),
+        size: data.len(),
+        // Simple implementation without attachment parsing
+        ..Default::default()
     };
 
-    // Store email
-    {
-        let mut emails = state.emails.write().unwrap();
-        emails.push(email.clone());
-    }
+    store_email(&state, email, data)
+}
+
+/// Record a header field collected by the simple parser
+///
+/// `field` is the complete field, with continuation lines separated by CRLF.
+fn push_simple_header(
+    field: &str,
+    headers: &mut HashMap<String, String>,
+    header_list: &mut Vec<Header>,
+) {
+    if let Some((name, raw)) = field.split_once(':') {
+        let name = name.trim().to_string();
+        let value = unfold(raw);
+        headers.insert(name.clone(), value.clone());
+        header_list.push(Header {
+            name,
+            raw: raw.to_string(),
+            value,
+        });
+    }
+}
+
+/// Store a processed email, broadcast it to WebSocket clients and apply the retention limits
+fn store_email(state: &AppState, mut email: Email, data: &[u8]) -> io::Result<()> {
+    state.threads.assign(&mut email);
+    if let Err(e) = state.storage.insert(email.clone(), data) {
+        state.threads.remove(&email.id);
+        return Err(e);
+    }
+    state.index.insert(&email);
 
     // Broadcast to websocket clients
-    let _ = state.tx.send(email);
+    let _ = state.tx.send(EmailEvent::Created {
+        email: Box::new(email),
+    });
+
+    // A failed eviction must not reject the message that was just accepted
+    if let Err(e) = retention::prune(state) {
+        warn!("Failed to prune emails: {}", e);
+    }
 
     Ok(())
 }
 
+/// Collect the message IDs of an `In-Reply-To` or `References` header
+fn message_ids(value: &HeaderValue) -> Vec<String> {
+    value
+        .as_text_list()
+        .map(|ids| ids.iter().map(|id| id.to_string()).collect())
+        .unwrap_or_default()
+}
+
 /// Start the SMTP server
 ///
 /// Binds to the specified port and listens for incoming SMTP connections.
+/// When an SMTPS port is configured, an implicit TLS listener is started alongside it.
 /// Each connection is handled in a separate task.
-pub async fn start_smtp_server(state: Arc<AppState>, port: u16) {
+pub async fn start_smtp_server(state: Arc<AppState>, port: u16, config: Arc<SmtpConfig>) {
+    if let Some(smtps_port) = config.smtps_port {
+        let state_clone = state.clone();
+        let config_clone = config.clone();
+        tokio::spawn(async move {
+            run_listener(state_clone, smtps_port, config_clone, true).await;
+        });
+    }
+
+    run_listener(state, port, config, false).await;
+}
+
+/// Accept connections on a single SMTP listener
+///
+/// With `implicit_tls`, every connection performs the TLS handshake before the greeting.
+async fn run_listener(
+    state: Arc<AppState>,
+    port: u16,
+    config: Arc<SmtpConfig>,
+    implicit_tls: bool,
+) {
     let smtp_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let kind = if implicit_tls { "SMTPS" } else { "SMTP" };
 
-    info!("Starting SMTP server on {}", smtp_addr);
+    info!("Starting {} server on {}", kind, smtp_addr);
 
     // Create a TCP listener for the SMTP server
     match TcpListener::bind(smtp_addr).await {
         Ok(listener) => {
-            info!("SMTP server listening on {}", smtp_addr);
+            info!("{} server listening on {}", kind, smtp_addr);
 
             // Accept connections and handle them
             loop {
                 match listener.accept().await {
                     Ok((stream, addr)) => {
                         let state_clone = state.clone();
+                        let config_clone = config.clone();
 
                         // Handle the connection
                         tokio::spawn(async move {
-                            if let Err(e) = handle_smtp_client(stream, addr, state_clone).await {
-                                warn!("SMTP session error: {}", e);
+                            let result = if implicit_tls {
+                                handle_smtps_client(stream, addr, state_clone, config_clone).await
+                            } else {
+                                handle_smtp_client(stream, addr, state_clone, config_clone).await
+                            };
+                            if let Err(e) = result {
+                                warn!("{} session error: {}", kind, e);
                             }
                         });
                     }
                     Err(e) => {
-                        warn!("Failed to accept SMTP connection: {}", e);
+                        warn!("Failed to accept {} connection: {}", kind, e);
                     }
                 }
             }
         }
         Err(e) => {
-            warn!("Failed to bind SMTP server: {}", e);
+            warn!("Failed to bind {} server: {}", kind, e);
         }
     }
 }
@@ -449,20 +1415,27 @@ pub async fn start_smtp_server(state: Arc<AppState>, port: u16) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::MemoryStorage;
     use std::sync::Arc;
-    use std::sync::RwLock;
-    use tokio::sync::broadcast;
+    use std::time::Duration;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
-    use std::time::Duration;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 
     // Helper function to create a test AppState
     fn create_test_state() -> Arc<AppState> {
-        let (tx, _) = broadcast::channel(100);
-        Arc::new(AppState {
-            emails: RwLock::new(Vec::<Email>::new()),
-            tx,
-        })
+        Arc::new(AppState::new(Box::new(MemoryStorage::default())))
+    }
+
+    // Helper function to create an envelope without session details
+    fn test_envelope(from: String, to: Vec<String>) -> Envelope {
+        Envelope {
+            from,
+            to,
+            ..Default::default()
+        }
     }
 
     #[tokio::test]
@@ -478,17 +1451,27 @@ mod tests {
                           \r\n\
                           This is a test email body.";
 
-        let result = process_email_simple(email_data, from, to.clone(), state.clone()).await;
+        let result = process_email_simple(
+            email_data.as_bytes(),
+            test_envelope(from, to.clone()),
+            state.clone(),
+        )
+        .await;
         assert!(result.is_ok());
 
         // Verify the email was stored
-        let emails = state.emails.read().unwrap();
+        let emails = state.storage.list();
         assert_eq!(emails.len(), 1);
 
         let email = &emails[0];
         assert_eq!(email.from, "sender@example.com");
         assert_eq!(email.to, to);
         assert_eq!(email.subject, "Test Email");
+        assert_eq!(
+            email.addresses.to[0].address.as_deref(),
+            Some("recipient@example.com")
+        );
+        assert!(email.bcc.is_empty());
         assert_eq!(
             email.text_body,
             Some("This is a test email body.".to_string())
@@ -510,17 +1493,207 @@ mod tests {
             .as_bytes()
             .to_vec();
 
-        let result = process_email(&email_data, from, to.clone(), state.clone()).await;
+        let result =
+            process_email(&email_data, test_envelope(from, to.clone()), state.clone()).await;
         assert!(result.is_ok());
 
         // Verify the email was stored
-        let emails = state.emails.read().unwrap();
+        let emails = state.storage.list();
         assert_eq!(emails.len(), 1);
 
         let email = &emails[0];
         assert_eq!(email.from, "sender@example.com");
         assert_eq!(email.to, to);
         assert!(email.subject.contains("Test Email"));
+
+        // Indexed for full-text search
+        assert!(
+            state
+                .index
+                .search("\"test email body\"")
+                .contains(&email.id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_email_header_list() {
+        let state = create_test_state();
+        let email_data = b"Received: from a.example.com\r\n\
+                           \tby mx.example.com; Mon, 1 Jan 2024 00:00:01 +0000\r\n\
+                           Received: from b.example.com by a.example.com\r\n\
+                           Subject: =?UTF-8?Q?Caf=C3=A9?=\r\n\
+                           X-Custom: one\r\n\
+                           X-Custom: two\r\n\
+                           \r\n\
+                           body";
+
+        process_email(
+            email_data,
+            test_envelope("a@example.com".to_string(), vec![]),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let emails = state.storage.list();
+        let header_list = &emails[0].header_list;
+        let names: Vec<&str> = header_list.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["Received", "Received", "Subject", "X-Custom", "X-Custom"]
+        );
+
+        // Folding is kept in the raw value and removed from the decoded value
+        assert_eq!(
+            header_list[0].raw,
+            " from a.example.com\r\n\tby mx.example.com; Mon, 1 Jan 2024 00:00:01 +0000"
+        );
+        assert_eq!(
+            header_list[0].value,
+            "from a.example.com by mx.example.com; Mon, 1 Jan 2024 00:00:01 +0000"
+        );
+        assert_eq!(header_list[2].raw, " =?UTF-8?Q?Caf=C3=A9?=");
+        assert_eq!(header_list[2].value, "Café");
+        assert_eq!(header_list[3].value, "one");
+        assert_eq!(header_list[4].value, "two");
+
+        // The convenience map keeps the last value
+        assert_eq!(emails[0].headers["X-Custom"], "two");
+    }
+
+    #[tokio::test]
+    async fn test_process_email_addresses() {
+        let state = create_test_state();
+        let email_data = b"From: \"Doe, Jane\" <jane@example.com>\r\n\
+                           Reply-To: replies@example.com\r\n\
+                           To: Team: alice@example.com, Bob <bob@example.com>;, carol@example.com\r\n\
+                           Cc: undisclosed-recipients:;\r\n\
+                           Bcc: Just A Name\r\n\
+                           Subject: Addresses\r\n\
+                           \r\n\
+                           body";
+
+        process_email(
+            email_data,
+            test_envelope("jane@example.com".to_string(), vec![]),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let emails = state.storage.list();
+        let addresses = &emails[0].addresses;
+        assert_eq!(
+            addresses.from,
+            vec![EmailAddress {
+                name: Some("Doe, Jane".to_string()),
+                address: Some("jane@example.com".to_string()),
+                group: None,
+            }]
+        );
+        assert_eq!(
+            addresses.reply_to[0].address.as_deref(),
+            Some("replies@example.com")
+        );
+        assert!(addresses.sender.is_empty());
+
+        // Group members carry the group name
+        let to: Vec<(Option<&str>, Option<&str>)> = addresses
+            .to
+            .iter()
+            .map(|a| (a.address.as_deref(), a.group.as_deref()))
+            .collect();
+        assert_eq!(
+            to,
+            vec![
+                (Some("alice@example.com"), Some("Team")),
+                (Some("bob@example.com"), Some("Team")),
+                (Some("carol@example.com"), None),
+            ]
+        );
+        assert_eq!(addresses.to[1].name.as_deref(), Some("Bob"));
+
+        // Empty groups and address-less entries are kept without panicking
+        assert_eq!(
+            addresses.cc,
+            vec![EmailAddress {
+                group: Some("undisclosed-recipients".to_string()),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(addresses.bcc.len(), 1);
+        assert_eq!(addresses.bcc[0].address, None);
+
+        // Header values are plain text, escaping is left to the frontend
+        assert_eq!(emails[0].headers["From"], "Doe, Jane <jane@example.com>");
+        assert_eq!(
+            emails[0].headers["To"],
+            "Team: alice@example.com, Bob <bob@example.com>;, carol@example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_email_hidden_recipients() {
+        let state = create_test_state();
+        let email_data = b"To: Alice <Alice@example.com>\r\n\
+                           Cc: Team: carol@example.com;\r\n\
+                           Bcc: dave@example.com\r\n\
+                           Subject: Newsletter\r\n\
+                           \r\n\
+                           body";
+        let to = vec![
+            "alice@example.com".to_string(),
+            "bob@example.com".to_string(),
+            "carol@example.com".to_string(),
+            "dave@example.com".to_string(),
+        ];
+
+        process_email(
+            email_data,
+            test_envelope("news@example.com".to_string(), to.clone()),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        // The fallback parser finds the same hidden recipients
+        process_email_simple(
+            email_data,
+            test_envelope("news@example.com".to_string(), to),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+
+        // The Bcc header is not visible to the recipients, so dave counts as hidden
+        assert_eq!(state.storage.list().len(), 2);
+        for email in state.storage.list() {
+            assert_eq!(email.bcc, vec!["bob@example.com", "dave@example.com"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_email_simple_header_list() {
+        let state = create_test_state();
+        let email_data = "Subject: folded\r\n\
+                          \tsubject\r\n\
+                          X-Last: kept\r\n\
+                          \r\n\
+                          body";
+
+        process_email_simple(
+            email_data.as_bytes(),
+            test_envelope("a@example.com".to_string(), vec![]),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let emails = state.storage.list();
+        assert_eq!(emails[0].subject, "folded subject");
+        assert!(emails[0].addresses.to.is_empty());
+        assert_eq!(emails[0].header_list[0].raw, " folded\r\n\tsubject");
+        assert_eq!(emails[0].header_list[1].name, "X-Last");
+        assert_eq!(emails[0].headers["X-Last"], "kept");
     }
 
     #[tokio::test]
@@ -548,11 +1721,12 @@ mod tests {
             .as_bytes()
             .to_vec();
 
-        let result = process_email(&email_data, from, to.clone(), state.clone()).await;
+        let result =
+            process_email(&email_data, test_envelope(from, to.clone()), state.clone()).await;
         assert!(result.is_ok());
 
         // Verify the email was stored
-        let emails = state.emails.read().unwrap();
+        let emails = state.storage.list();
         assert_eq!(emails.len(), 1);
 
         let email = &emails[0];
@@ -571,6 +1745,183 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_process_email_with_attached_message() {
+        let state = create_test_state();
+        let from = "sender@example.com".to_string();
+        let to = vec!["recipient@example.com".to_string()];
+
+        // Forward an email that has an attachment of its own
+        let email_data = "From: sender@example.com\r\n\
+                          To: recipient@example.com\r\n\
+                          Subject: Fwd: Report\r\n\
+                          Content-Type: multipart/mixed; boundary=outer\r\n\
+                          \r\n\
+                          --outer\r\n\
+                          Content-Type: text/plain\r\n\
+                          \r\n\
+                          See the forwarded message.\r\n\
+                          --outer\r\n\
+                          Content-Type: message/rfc822\r\n\
+                          \r\n\
+                          From: Original <original@example.com>\r\n\
+                          Subject: Report\r\n\
+                          X-Report: yes\r\n\
+                          Content-Type: multipart/mixed; boundary=inner\r\n\
+                          \r\n\
+                          --inner\r\n\
+                          Content-Type: text/plain\r\n\
+                          \r\n\
+                          The report is attached.\r\n\
+                          --inner\r\n\
+                          Content-Type: text/csv\r\n\
+                          Content-Disposition: attachment; filename=\"report.csv\"\r\n\
+                          \r\n\
+                          a,b\r\n\
+                          --inner--\r\n\
+                          --outer--\r\n"
+            .as_bytes()
+            .to_vec();
+
+        let result = process_email(&email_data, test_envelope(from, to), state.clone()).await;
+        assert!(result.is_ok());
+
+        let emails = state.storage.list();
+        let email = &emails[0];
+        // The forwarded message is a child email rather than an attachment
+        assert!(email.attachments.is_empty());
+        assert_eq!(email.attached_messages.len(), 1);
+
+        let child = &email.attached_messages[0];
+        assert_eq!(child.id, format!("{}.1", email.id));
+        assert_eq!(child.parent_id.as_deref(), Some(email.id.as_str()));
+        assert_eq!(child.subject, "Report");
+        assert_eq!(
+            child.addresses.from[0].address.as_deref(),
+            Some("original@example.com")
+        );
+        assert_eq!(child.header_list[2].raw, " yes");
+        assert_eq!(child.text_body.as_deref(), Some("The report is attached."));
+        assert_eq!(child.attachments[0].filename, "report.csv");
+        assert_eq!(child.attachments[0].data.as_deref(), Some(&b"a,b"[..]));
+        assert_eq!(child.mailbox, email.mailbox);
+        assert!(child.to.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_email_dsn() {
+        let state = create_test_state();
+        let email_data = "From: MAILER-DAEMON@example.com\r\n\
+                          Subject: Undelivered Mail Returned to Sender\r\n\
+                          Content-Type: multipart/report; report-type=delivery-status; boundary=b\r\n\
+                          \r\n\
+                          --b\r\n\
+                          Content-Type: text/plain\r\n\
+                          \r\n\
+                          Delivery failed.\r\n\
+                          --b\r\n\
+                          Content-Type: message/delivery-status\r\n\
+                          \r\n\
+                          Reporting-MTA: dns; mx.example.com\r\n\
+                          \r\n\
+                          Final-Recipient: rfc822; missing@example.com\r\n\
+                          Action: failed\r\n\
+                          Status: 5.1.1\r\n\
+                          --b--\r\n"
+            .as_bytes()
+            .to_vec();
+        let envelope = test_envelope(String::new(), vec!["sender@example.com".to_string()]);
+
+        process_email(&email_data, envelope, state.clone())
+            .await
+            .unwrap();
+
+        let emails = state.storage.list();
+        let dsn = emails[0].dsn.as_ref().unwrap();
+        assert_eq!(dsn.reporting_mta.as_deref(), Some("dns; mx.example.com"));
+        assert_eq!(dsn.recipients.len(), 1);
+        assert_eq!(dsn.recipients[0].status.as_deref(), Some("5.1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_process_email_calendar() {
+        let state = create_test_state();
+        let email_data = "From: booking@example.com\r\n\
+                          Subject: Invitation\r\n\
+                          Content-Type: multipart/alternative; boundary=b\r\n\
+                          \r\n\
+                          --b\r\n\
+                          Content-Type: text/plain\r\n\
+                          \r\n\
+                          You are invited.\r\n\
+                          --b\r\n\
+                          Content-Type: text/calendar; method=REQUEST\r\n\
+                          \r\n\
+                          BEGIN:VCALENDAR\r\n\
+                          METHOD:REQUEST\r\n\
+                          BEGIN:VEVENT\r\n\
+                          UID:event-1@example.com\r\n\
+                          SUMMARY:Planning\r\n\
+                          ATTENDEE:mailto:alice@example.com\r\n\
+                          END:VEVENT\r\n\
+                          END:VCALENDAR\r\n\
+                          --b--\r\n"
+            .as_bytes()
+            .to_vec();
+        let envelope = test_envelope(
+            "booking@example.com".to_string(),
+            vec!["alice@example.com".to_string()],
+        );
+
+        process_email(&email_data, envelope, state.clone())
+            .await
+            .unwrap();
+
+        let emails = state.storage.list();
+        let calendar = emails[0].calendar.as_ref().unwrap();
+        assert_eq!(calendar.method.as_deref(), Some("REQUEST"));
+        assert_eq!(
+            calendar.events[0].uid.as_deref(),
+            Some("event-1@example.com")
+        );
+        assert_eq!(calendar.events[0].summary.as_deref(), Some("Planning"));
+        assert_eq!(calendar.events[0].attendees[0].address, "alice@example.com");
+        assert_eq!(emails[0].attachments.len(), 1);
+        assert_eq!(emails[0].attachments[0].content_type, "text/calendar");
+    }
+
+    #[tokio::test]
+    async fn test_process_email_threads() {
+        let state = create_test_state();
+        let messages = [
+            "Message-ID: <1@example.com>\r\nSubject: Hello\r\n\r\nHello\r\n",
+            "Message-ID: <2@example.com>\r\nSubject: Other\r\n\r\nOther\r\n",
+            "Message-ID: <3@example.com>\r\n\
+             In-Reply-To: <1@example.com>\r\n\
+             References: <1@example.com>\r\n\
+             Subject: Re: Hello\r\n\
+             \r\n\
+             Hi\r\n",
+        ];
+        for message in messages {
+            let envelope = test_envelope(
+                "sender@example.com".to_string(),
+                vec!["recipient@example.com".to_string()],
+            );
+            process_email(message.as_bytes(), envelope, state.clone())
+                .await
+                .unwrap();
+        }
+
+        let emails = state.storage.list();
+        assert_eq!(emails[2].message_id.as_deref(), Some("3@example.com"));
+        assert_eq!(emails[2].in_reply_to, vec!["1@example.com"]);
+        assert_eq!(emails[2].references, vec!["1@example.com"]);
+        assert_eq!(emails[0].thread_id, emails[0].id);
+        assert_eq!(emails[1].thread_id, emails[1].id);
+        assert_eq!(emails[2].thread_id, emails[0].id);
+    }
+
     #[tokio::test]
     async fn test_process_email_with_html() {
         let state = create_test_state();
@@ -595,11 +1946,12 @@ mod tests {
             .as_bytes()
             .to_vec();
 
-        let result = process_email(&email_data, from, to.clone(), state.clone()).await;
+        let result =
+            process_email(&email_data, test_envelope(from, to.clone()), state.clone()).await;
         assert!(result.is_ok());
 
         // Verify the email was stored
-        let emails = state.emails.read().unwrap();
+        let emails = state.storage.list();
         assert_eq!(emails.len(), 1);
 
         let email = &emails[0];
@@ -614,8 +1966,21 @@ mod tests {
             assert!(text_body.contains("This is a test email body"));
         }
         if let Some(html_body) = &email.html_body {
-            assert!(html_body.contains("<html><body><p>This is a test email body.</p></body></html>"));
+            assert!(
+                html_body.contains("<html><body><p>This is a test email body.</p></body></html>")
+            );
         }
+
+        // Check the MIME structure
+        let mime = email.mime.as_ref().unwrap();
+        assert_eq!(mime.content_type, "multipart/alternative");
+        let types: Vec<&str> = mime
+            .children
+            .iter()
+            .map(|part| part.content_type.as_str())
+            .collect();
+        assert_eq!(types, vec!["text/plain", "text/html"]);
+        assert_eq!(mime.children[1].path, "1.2");
     }
 
     #[tokio::test]
@@ -630,7 +1995,13 @@ mod tests {
         let state_clone = state.clone();
         let handle_task = tokio::spawn(async move {
             let (stream, client_addr) = listener.accept().await.unwrap();
-            handle_smtp_client(stream, client_addr, state_clone).await
+            handle_smtp_client(
+                stream,
+                client_addr,
+                state_clone,
+                Arc::new(SmtpConfig::default()),
+            )
+            .await
         });
 
         // Connect to the server
@@ -646,19 +2017,30 @@ mod tests {
         stream.write_all(b"EHLO example.com\r\n").await.unwrap();
         let n = stream.read(&mut buffer).await.unwrap();
         let response = String::from_utf8_lossy(&buffer[..n]);
-        assert!(response.contains("250 MailHits"));
+        assert!(response.contains("250-MailHits"));
+        assert!(response.contains("250-SIZE"));
+        assert!(response.contains("250-8BITMIME"));
+        assert!(response.contains("250-PIPELINING"));
+        assert!(response.contains("250-ENHANCEDSTATUSCODES"));
+        assert!(response.contains("250 SMTPUTF8"));
 
         // Send MAIL FROM
-        stream.write_all(b"MAIL FROM:<sender@example.com>\r\n").await.unwrap();
+        stream
+            .write_all(b"MAIL FROM:<sender@example.com>\r\n")
+            .await
+            .unwrap();
         let n = stream.read(&mut buffer).await.unwrap();
         let response = String::from_utf8_lossy(&buffer[..n]);
-        assert!(response.contains("250 OK"));
+        assert!(response.contains("250 2.1.0 OK"));
 
         // Send RCPT TO
-        stream.write_all(b"RCPT TO:<recipient@example.com>\r\n").await.unwrap();
+        stream
+            .write_all(b"RCPT TO:<recipient@example.com>\r\n")
+            .await
+            .unwrap();
         let n = stream.read(&mut buffer).await.unwrap();
         let response = String::from_utf8_lossy(&buffer[..n]);
-        assert!(response.contains("250 OK"));
+        assert!(response.contains("250 2.1.5 OK"));
 
         // Send DATA command
         stream.write_all(b"DATA\r\n").await.unwrap();
@@ -667,28 +2049,37 @@ mod tests {
         assert!(response.contains("354 Start mail input"));
 
         // Send email content
-        stream.write_all(b"From: sender@example.com\r\n").await.unwrap();
-        stream.write_all(b"To: recipient@example.com\r\n").await.unwrap();
+        stream
+            .write_all(b"From: sender@example.com\r\n")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"To: recipient@example.com\r\n")
+            .await
+            .unwrap();
         stream.write_all(b"Subject: Test Email\r\n").await.unwrap();
         stream.write_all(b"\r\n").await.unwrap();
-        stream.write_all(b"This is a test email body.\r\n").await.unwrap();
+        stream
+            .write_all(b"This is a test email body.\r\n")
+            .await
+            .unwrap();
         stream.write_all(b".\r\n").await.unwrap();
 
         let n = stream.read(&mut buffer).await.unwrap();
         let response = String::from_utf8_lossy(&buffer[..n]);
-        assert!(response.contains("250 OK: Message accepted"));
+        assert!(response.contains("250 2.0.0 OK: Message accepted"));
 
         // Send QUIT
         stream.write_all(b"QUIT\r\n").await.unwrap();
         let n = stream.read(&mut buffer).await.unwrap();
         let response = String::from_utf8_lossy(&buffer[..n]);
-        assert!(response.contains("221 MailHits closing connection"));
+        assert!(response.contains("221 2.0.0 MailHits closing connection"));
 
         // Wait for the handler to complete
         handle_task.await.unwrap().unwrap();
 
         // Verify the email was stored
-        let emails = state.emails.read().unwrap();
+        let emails = state.storage.list();
         assert_eq!(emails.len(), 1);
 
         let email = &emails[0];
@@ -697,6 +2088,754 @@ mod tests {
         assert!(email.subject.contains("Test Email"));
     }
 
+    #[tokio::test]
+    async fn test_handle_smtp_client_8bit_data() {
+        let state = create_test_state();
+
+        // Create a TCP listener for the test
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Spawn a task to handle the client connection
+        let state_clone = state.clone();
+        let handle_task = tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            handle_smtp_client(
+                stream,
+                client_addr,
+                state_clone,
+                Arc::new(SmtpConfig::default()),
+            )
+            .await
+        });
+
+        // Connect to the server
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        for command in [
+            &b"HELO example.com\r\n"[..],
+            b"MAIL FROM:<sender@example.com>\r\n",
+            b"RCPT TO:<recipient@example.com>\r\n",
+            b"DATA\r\n",
+        ] {
+            stream.write_all(command).await.unwrap();
+            let _ = stream.read(&mut buffer).await.unwrap();
+        }
+
+        // Send a Latin-1 body, which is not valid UTF-8, plus a dot-stuffed line
+        stream
+            .write_all(b"From: sender@example.com\r\n")
+            .await
+            .unwrap();
+        stream.write_all(b"Subject: 8bit\r\n").await.unwrap();
+        stream
+            .write_all(b"Content-Type: text/plain; charset=iso-8859-1\r\n")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"Content-Transfer-Encoding: 8bit\r\n")
+            .await
+            .unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+        stream.write_all(b"Caf\xe9 cr\xe8me\r\n").await.unwrap();
+        stream.write_all(b"..leading dot\r\n").await.unwrap();
+        stream.write_all(b".\r\n").await.unwrap();
+
+        let n = stream.read(&mut buffer).await.unwrap();
+        let response = String::from_utf8_lossy(&buffer[..n]);
+        assert!(response.contains("250 OK: Message accepted"));
+
+        stream.write_all(b"QUIT\r\n").await.unwrap();
+        let _ = stream.read(&mut buffer).await.unwrap();
+        handle_task.await.unwrap().unwrap();
+
+        let emails = state.storage.list();
+        assert_eq!(emails.len(), 1);
+        let text_body = emails[0].text_body.as_deref().unwrap();
+        assert!(text_body.contains("Café crème"));
+        assert!(text_body.contains("\n.leading dot"));
+
+        // The raw source keeps the exact bytes, minus the dot-stuffing
+        let raw = state.storage.raw(&emails[0].id).unwrap().unwrap();
+        assert_eq!(
+            raw,
+            b"From: sender@example.com\r\n\
+              Subject: 8bit\r\n\
+              Content-Type: text/plain; charset=iso-8859-1\r\n\
+              Content-Transfer-Encoding: 8bit\r\n\
+              \r\n\
+              Caf\xe9 cr\xe8me\r\n\
+              .leading dot\r\n"
+        );
+    }
+
+    #[test]
+    fn test_data_line_helpers() {
+        assert!(is_data_terminator(b".\r\n"));
+        assert!(is_data_terminator(b".\n"));
+        assert!(!is_data_terminator(b"..\r\n"));
+        assert!(!is_data_terminator(b"."));
+        assert_eq!(unstuff_data_line(b"..\r\n"), b".\r\n");
+        assert_eq!(unstuff_data_line(b"a\rb\xff\r\n"), b"a\rb\xff\r\n");
+    }
+
+    // Helper function to run a single SMTP session against a test listener
+    async fn spawn_test_session(
+        state: Arc<AppState>,
+        config: SmtpConfig,
+    ) -> (TcpStream, tokio::task::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle_task = tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            handle_smtp_client(stream, client_addr, state, Arc::new(config)).await
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+        (stream, handle_task)
+    }
+
+    // Helper function to send a command and return the server response
+    async fn send_command<S>(stream: &mut S, command: &str) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buffer = [0; 4096];
+        stream.write_all(command.as_bytes()).await.unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_esmtp_mail_parameters() {
+        let state = create_test_state();
+        let config = SmtpConfig {
+            max_message_size: 64,
+            ..Default::default()
+        };
+        let (mut stream, handle_task) = spawn_test_session(state.clone(), config).await;
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(response.contains("250-SIZE 64\r\n"));
+
+        // SIZE larger than the limit is rejected up front
+        let response = send_command(&mut stream, "MAIL FROM:<a@example.com> SIZE=65\r\n").await;
+        assert!(response.starts_with("552 5.3.4"));
+
+        // Unknown BODY types and parameters are rejected
+        let response =
+            send_command(&mut stream, "MAIL FROM:<a@example.com> BODY=BINARYMIME\r\n").await;
+        assert!(response.starts_with("501 5.5.4"));
+        let response = send_command(&mut stream, "MAIL FROM:<a@example.com> FOO=BAR\r\n").await;
+        assert!(response.starts_with("555 5.5.4"));
+
+        // UTF-8 addresses require SMTPUTF8
+        let response = send_command(&mut stream, "MAIL FROM:<j\u{f6}rg@example.com>\r\n").await;
+        assert!(response.starts_with("553 5.6.7"));
+        let response = send_command(
+            &mut stream,
+            "mail from:<j\u{f6}rg@example.com> SIZE=10 BODY=8BITMIME SMTPUTF8\r\n",
+        )
+        .await;
+        assert!(response.starts_with("250 2.1.0"));
+        let response =
+            send_command(&mut stream, "RCPT TO:<\u{7528}\u{6237}@example.com>\r\n").await;
+        assert!(response.starts_with("250 2.1.5"));
+
+        // Messages larger than SIZE are rejected after the terminator
+        let response = send_command(&mut stream, "DATA\r\n").await;
+        assert!(response.starts_with("354"));
+        let body = format!("Subject: big\r\n\r\n{}\r\n.\r\n", "x".repeat(100));
+        let response = send_command(&mut stream, &body).await;
+        assert!(response.starts_with("552 5.3.4"));
+        assert!(state.storage.list().is_empty());
+
+        let response = send_command(&mut stream, "QUIT\r\n").await;
+        assert!(response.starts_with("221 2.0.0"));
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_data_aborted_by_disconnect() {
+        let state = create_test_state();
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+        send_command(&mut stream, "MAIL FROM:<a@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<b@example.com>\r\n").await;
+        let response = send_command(&mut stream, "DATA\r\n").await;
+        assert!(response.starts_with("354"));
+
+        // The connection drops after a dot without a line break: the message is incomplete
+        stream
+            .write_all(b"Subject: cut\r\n\r\nbody\r\n.")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        handle_task.await.unwrap().unwrap();
+        assert!(state.storage.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_overlong_lines() {
+        let state = create_test_state();
+        let config = SmtpConfig {
+            max_message_size: 64,
+            ..Default::default()
+        };
+        let (mut stream, handle_task) = spawn_test_session(state.clone(), config).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+
+        // A command line longer than the limit is rejected as a whole
+        let command = format!("NOOP {}\r\n", "x".repeat(MAX_LINE_LENGTH * 2));
+        let response = send_command(&mut stream, &command).await;
+        assert_eq!(response, "500 5.5.2 Line too long\r\n");
+        let response = send_command(&mut stream, "NOOP\r\n").await;
+        assert!(response.starts_with("250"));
+
+        // A message line without an end is cut off at the size limit; a dot in the
+        // middle of it is not taken for the terminator
+        send_command(&mut stream, "MAIL FROM:<a@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<b@example.com>\r\n").await;
+        let response = send_command(&mut stream, "DATA\r\n").await;
+        assert!(response.starts_with("354"));
+        let line = format!("{}.\r\n", "x".repeat(65 + MAX_LINE_LENGTH * 2));
+        stream.write_all(line.as_bytes()).await.unwrap();
+        let response = send_command(&mut stream, ".\r\n").await;
+        assert_eq!(response, "552 5.3.4 Message size exceeds fixed maximum\r\n");
+        let response = send_command(&mut stream, "NOOP\r\n").await;
+        assert!(response.starts_with("250"));
+        assert!(state.storage.list().is_empty());
+
+        let response = send_command(&mut stream, "QUIT\r\n").await;
+        assert!(response.starts_with("221 2.0.0"));
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_message_size() {
+        let state = create_test_state();
+        let config = SmtpConfig {
+            max_message_size: usize::MAX,
+            ..Default::default()
+        };
+        let (mut stream, handle_task) = spawn_test_session(state.clone(), config).await;
+
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+        send_command(&mut stream, "MAIL FROM:<a@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<b@example.com>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let response = send_command(&mut stream, "Subject: big\r\n\r\nbody\r\n.\r\n").await;
+        assert!(response.starts_with("250 2.0.0"));
+        assert_eq!(state.storage.list().len(), 1);
+
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dsn_parameters() {
+        let state = create_test_state();
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(response.contains("250-DSN\r\n"));
+
+        // Invalid values are rejected
+        let response = send_command(&mut stream, "MAIL FROM:<a@example.com> RET=BODY\r\n").await;
+        assert!(response.starts_with("501 5.5.4"));
+        let response = send_command(&mut stream, "MAIL FROM:<a@example.com> ENVID=a=b\r\n").await;
+        assert!(response.starts_with("501 5.5.4"));
+
+        let response = send_command(
+            &mut stream,
+            "MAIL FROM:<a@example.com> RET=hdrs ENVID=QQ+2B1\r\n",
+        )
+        .await;
+        assert!(response.starts_with("250 2.1.0"));
+        let response = send_command(
+            &mut stream,
+            "RCPT TO:<b@example.com> NOTIFY=NEVER,FAILURE\r\n",
+        )
+        .await;
+        assert!(response.starts_with("501 5.5.4"));
+        let response = send_command(&mut stream, "RCPT TO:<b@example.com> FOO=BAR\r\n").await;
+        assert!(response.starts_with("555 5.5.4"));
+        let response = send_command(
+            &mut stream,
+            "RCPT TO:<b@example.com> NOTIFY=failure,DELAY ORCPT=rfc822;B+2Bx@example.com\r\n",
+        )
+        .await;
+        assert!(response.starts_with("250 2.1.5"));
+        let response = send_command(&mut stream, "RCPT TO:<c@example.com>\r\n").await;
+        assert!(response.starts_with("250 2.1.5"));
+        let response = send_command(&mut stream, "RCPT TO:<d@example.com> NOTIFY=NEVER\r\n").await;
+        assert!(response.starts_with("250 2.1.5"));
+
+        send_command(&mut stream, "DATA\r\n").await;
+        let response = send_command(&mut stream, "Subject: DSN\r\n\r\nbody\r\n.\r\n").await;
+        assert!(response.starts_with("250 2.0.0"));
+
+        // Addresses no longer carry the parameters
+        let emails = state.storage.list();
+        assert_eq!(emails[0].from, "a@example.com");
+        assert_eq!(
+            emails[0].to,
+            vec!["b@example.com", "c@example.com", "d@example.com"]
+        );
+        assert_eq!(
+            emails[0].dsn_request,
+            Some(DsnRequest {
+                ret: Some("HDRS".to_string()),
+                envid: Some("QQ+1".to_string()),
+                recipients: vec![
+                    DsnRecipientRequest {
+                        address: "b@example.com".to_string(),
+                        notify: vec!["FAILURE".to_string(), "DELAY".to_string()],
+                        orcpt: Some("rfc822;B+x@example.com".to_string()),
+                    },
+                    DsnRecipientRequest {
+                        address: "d@example.com".to_string(),
+                        notify: vec!["NEVER".to_string()],
+                        orcpt: None,
+                    },
+                ],
+            })
+        );
+
+        // The parameters belong to a single transaction
+        send_command(&mut stream, "MAIL FROM:<a@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<b@example.com>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Subject: Plain\r\n\r\nbody\r\n.\r\n").await;
+        assert_eq!(state.storage.list()[1].dsn_request, None);
+
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_decode_xtext() {
+        assert_eq!(decode_xtext("plain").as_deref(), Some("plain"));
+        assert_eq!(decode_xtext("a+2Bb+3Dc").as_deref(), Some("a+b=c"));
+        assert_eq!(decode_xtext("").as_deref(), Some(""));
+        for invalid in ["a=b", "a+2", "a+ZZ", "a b"] {
+            assert!(decode_xtext(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_esmtp_pipelining() {
+        let state = create_test_state();
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+
+        // Send the whole envelope in a single write
+        stream
+            .write_all(
+                b"MAIL FROM:<>\r\nRCPT TO:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let mut buffer = [0; 1024];
+        while !response.contains("354") {
+            let n = stream.read(&mut buffer).await.unwrap();
+            response.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+        assert_eq!(response.matches("250 ").count(), 3);
+
+        let response = send_command(&mut stream, "Subject: bounce\r\n\r\nbody\r\n.\r\n").await;
+        assert!(response.starts_with("250 2.0.0"));
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+
+        let emails = state.storage.list();
+        assert_eq!(emails[0].from, "");
+        assert_eq!(emails[0].to, vec!["a@example.com", "b@example.com"]);
+    }
+
+    // Helper function to create a TLS client trusting the given certificates
+    fn test_tls_connector(certs: &[CertificateDer<'static>]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in certs {
+            roots.add(cert.clone()).unwrap();
+        }
+        let client_config = ClientConfig::builder_with_provider(Arc::new(
+            tokio_rustls::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        TlsConnector::from(Arc::new(client_config))
+    }
+
+    #[tokio::test]
+    async fn test_starttls() {
+        let state = create_test_state();
+        let (certs, key) = tls::generate_self_signed().unwrap();
+        let config = SmtpConfig {
+            tls: Some(tls::tls_acceptor(certs.clone(), key).unwrap()),
+            starttls: true,
+            ..Default::default()
+        };
+        let (mut stream, handle_task) = spawn_test_session(state.clone(), config).await;
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(response.contains("250 STARTTLS\r\n"));
+        let response = send_command(&mut stream, "STARTTLS\r\n").await;
+        assert!(response.starts_with("220 2.0.0"));
+
+        // Upgrade the connection, trusting the generated certificate
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = test_tls_connector(&certs)
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+
+        // STARTTLS is no longer offered once the channel is protected
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(!response.contains("STARTTLS"));
+        let response = send_command(&mut stream, "STARTTLS\r\n").await;
+        assert!(response.starts_with("503 5.5.1"));
+
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let response = send_command(&mut stream, "Subject: TLS\r\n\r\nsecret\r\n.\r\n").await;
+        assert!(response.starts_with("250 2.0.0"));
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+
+        let emails = state.storage.list();
+        let tls_info = emails[0].tls.as_ref().unwrap();
+        assert_eq!(tls_info.version, "TLSv1.3");
+        assert!(tls_info.cipher.starts_with("TLS13_"));
+        assert!(!tls_info.implicit);
+    }
+
+    #[tokio::test]
+    async fn test_handle_smtps_client() {
+        let state = create_test_state();
+        let (certs, key) = tls::generate_self_signed().unwrap();
+        let config = Arc::new(SmtpConfig {
+            tls: Some(tls::tls_acceptor(certs.clone(), key).unwrap()),
+            ..Default::default()
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state_clone = state.clone();
+        let handle_task = tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            handle_smtps_client(stream, client_addr, state_clone, config).await
+        });
+
+        // The TLS handshake happens before the greeting
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = test_tls_connector(&certs)
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+        let mut buffer = [0; 1024];
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..n]).contains("220 MailHits SMTP Server ready"));
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(!response.contains("STARTTLS"));
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let response = send_command(&mut stream, "Subject: SMTPS\r\n\r\nbody\r\n.\r\n").await;
+        assert!(response.starts_with("250 2.0.0"));
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+
+        let emails = state.storage.list();
+        let tls_info = emails[0].tls.as_ref().unwrap();
+        assert_eq!(tls_info.version, "TLSv1.3");
+        assert!(tls_info.implicit);
+    }
+
+    #[tokio::test]
+    async fn test_start_smtp_server_with_smtps() {
+        let state = create_test_state();
+        let (certs, key) = tls::generate_self_signed().unwrap();
+
+        // Find two available ports
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtps_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let smtps_port = smtps_listener.local_addr().unwrap().port();
+        drop(listener);
+        drop(smtps_listener);
+
+        let config = Arc::new(SmtpConfig {
+            tls: Some(tls::tls_acceptor(certs.clone(), key).unwrap()),
+            smtps_port: Some(smtps_port),
+            ..Default::default()
+        });
+        let server_task = tokio::spawn(start_smtp_server(state, port, config));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Both listeners accept connections
+        assert!(
+            TcpStream::connect(format!("127.0.0.1:{}", port))
+                .await
+                .is_ok()
+        );
+        let stream = TcpStream::connect(format!("127.0.0.1:{}", smtps_port))
+            .await
+            .unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = test_tls_connector(&certs)
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+        let mut buffer = [0; 1024];
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("220 "));
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_starttls_not_configured() {
+        let state = create_test_state();
+        let (mut stream, handle_task) = spawn_test_session(state, SmtpConfig::default()).await;
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(!response.contains("STARTTLS"));
+        let response = send_command(&mut stream, "STARTTLS\r\n").await;
+        assert!(response.starts_with("502 5.5.1"));
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_plain_and_login() {
+        let state = create_test_state();
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+
+        let response = send_command(&mut stream, "EHLO example.com\r\n").await;
+        assert!(response.contains("250-AUTH PLAIN LOGIN CRAM-MD5\r\n"));
+
+        // PLAIN with an initial response accepts any credentials by default
+        let command = format!("AUTH PLAIN {}\r\n", auth::encode("\0app-user\0pw"));
+        let response = send_command(&mut stream, &command).await;
+        assert!(response.starts_with("235 2.7.0"));
+        let response = send_command(&mut stream, &command).await;
+        assert!(response.starts_with("503 5.5.1"));
+
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Subject: AUTH\r\n\r\nbody\r\n.\r\n").await;
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+        assert_eq!(
+            state.storage.list()[0].auth_user.as_deref(),
+            Some("app-user")
+        );
+        assert_eq!(state.storage.list()[0].mailbox, "app-user");
+
+        // LOGIN prompts for the username and password
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+        let response = send_command(&mut stream, "AUTH LOGIN\r\n").await;
+        assert_eq!(response, "334 VXNlcm5hbWU6\r\n");
+        let response = send_command(&mut stream, &format!("{}\r\n", auth::encode("bob"))).await;
+        assert_eq!(response, "334 UGFzc3dvcmQ6\r\n");
+        let response = send_command(&mut stream, &format!("{}\r\n", auth::encode("pw"))).await;
+        assert!(response.starts_with("235 2.7.0"));
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_malformed_challenge_responses() {
+        let state = create_test_state();
+        let (mut stream, handle_task) =
+            spawn_test_session(state.clone(), SmtpConfig::default()).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+
+        // An overlong response is rejected as a whole and the session goes on
+        send_command(&mut stream, "AUTH LOGIN\r\n").await;
+        let line = format!("{}\r\n", "x".repeat(MAX_LINE_LENGTH * 2));
+        let response = send_command(&mut stream, &line).await;
+        assert_eq!(response, "501 5.5.2 Line too long\r\n");
+        let response = send_command(&mut stream, "NOOP\r\n").await;
+        assert!(response.starts_with("250"));
+
+        // So is a response that is not UTF-8
+        send_command(&mut stream, "AUTH LOGIN\r\n").await;
+        stream.write_all(b"\xff\xfe\r\n").await.unwrap();
+        let mut buffer = [0; 4096];
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"501 5.5.2 Cannot decode response\r\n");
+        let response = send_command(&mut stream, "NOOP\r\n").await;
+        assert!(response.starts_with("250"));
+
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_user_list() {
+        let state = create_test_state();
+        let config = SmtpConfig {
+            auth: AuthMode::from_user_list(&["alice:secret".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let (mut stream, handle_task) = spawn_test_session(state, config).await;
+        send_command(&mut stream, "EHLO example.com\r\n").await;
+
+        let command = format!("AUTH PLAIN {}\r\n", auth::encode("\0alice\0wrong"));
+        let response = send_command(&mut stream, &command).await;
+        assert!(response.starts_with("535 5.7.8"));
+
+        // Unknown mechanisms, malformed responses and cancellation are rejected
+        let response = send_command(&mut stream, "AUTH GSSAPI\r\n").await;
+        assert!(response.starts_with("504 5.5.4"));
+        let response = send_command(&mut stream, "AUTH PLAIN !!!\r\n").await;
+        assert!(response.starts_with("501 5.5.2"));
+        let response = send_command(&mut stream, "AUTH LOGIN\r\n").await;
+        assert!(response.starts_with("334 "));
+        let response = send_command(&mut stream, "*\r\n").await;
+        assert!(response.starts_with("501 5.7.0"));
+
+        // CRAM-MD5 is checked against the configured password
+        let response = send_command(&mut stream, "AUTH CRAM-MD5\r\n").await;
+        let challenge = auth::decode_text(response.trim().strip_prefix("334 ").unwrap()).unwrap();
+        let digest = auth::cram_md5_digest("secret", &challenge);
+        let answer = auth::encode(&format!("alice {}", digest));
+        let response = send_command(&mut stream, &format!("{}\r\n", answer)).await;
+        assert!(response.starts_with("235 2.7.0"));
+
+        send_command(&mut stream, "QUIT\r\n").await;
+        handle_task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_resolve_mailbox() {
+        let rules = vec![
+            MailboxRule::parse("team-a.test=team-a").unwrap(),
+            MailboxRule::parse("@Team-B.test=team-b").unwrap(),
+        ];
+        let rcpt = |addresses: &[&str]| -> Vec<String> {
+            addresses.iter().map(|a| a.to_string()).collect()
+        };
+
+        assert_eq!(
+            resolve_mailbox(Some("ci-job-1"), &rcpt(&["x@team-a.test"]), &rules),
+            "ci-job-1"
+        );
+        assert_eq!(
+            resolve_mailbox(None, &rcpt(&["x@other.test", "y@mail.team-b.test"]), &rules),
+            "team-b"
+        );
+        assert_eq!(
+            resolve_mailbox(None, &rcpt(&["x@TEAM-A.test"]), &rules),
+            "team-a"
+        );
+        assert_eq!(
+            resolve_mailbox(None, &rcpt(&["x@notteam-a.test"]), &rules),
+            DEFAULT_MAILBOX
+        );
+
+        assert!(MailboxRule::parse("team-a.test").is_none());
+        assert!(MailboxRule::parse("=team-a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_process_email_default_mailbox() {
+        let state = create_test_state();
+        let email_data = b"Subject: Test\r\n\r\nbody";
+
+        let envelope = test_envelope("a@example.com".to_string(), vec![]);
+        process_email(email_data, envelope, state.clone())
+            .await
+            .unwrap();
+
+        let envelope = Envelope {
+            mailbox: Some("team-a".to_string()),
+            ..test_envelope("a@example.com".to_string(), vec![])
+        };
+        process_email(email_data, envelope, state.clone())
+            .await
+            .unwrap();
+
+        let emails = state.storage.list();
+        assert_eq!(emails[0].mailbox, DEFAULT_MAILBOX);
+        assert_eq!(emails[1].mailbox, "team-a");
+    }
+
+    #[tokio::test]
+    async fn test_process_email_applies_retention() {
+        let mut state = AppState::new(Box::new(MemoryStorage::default()));
+        state.retention.max_count = Some(1);
+        let state = Arc::new(state);
+        let mut rx = state.tx.subscribe();
+
+        for subject in ["First", "Second"] {
+            let email_data = format!("Subject: {}\r\n\r\nbody", subject);
+            let envelope = test_envelope("a@example.com".to_string(), vec![]);
+            process_email(email_data.as_bytes(), envelope, state.clone())
+                .await
+                .unwrap();
+        }
+
+        let emails = state.storage.list();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].subject, "Second");
+        assert_eq!(emails[0].size, 23);
+
+        let first_id = match rx.recv().await.unwrap() {
+            EmailEvent::Created { email } => email.id,
+            event => panic!("unexpected event: {:?}", event),
+        };
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            EmailEvent::Created { .. }
+        ));
+        match rx.recv().await.unwrap() {
+            EmailEvent::Deleted { id } => assert_eq!(id, first_id),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_parse_path_argument() {
+        let (path, params) =
+            parse_path_argument("FROM:<a@example.com> SIZE=100 body=8bitmime", "FROM:").unwrap();
+        assert_eq!(path, "a@example.com");
+        assert_eq!(
+            params,
+            vec![
+                ("SIZE".to_string(), Some("100".to_string())),
+                ("BODY".to_string(), Some("8bitmime".to_string())),
+            ]
+        );
+
+        let (path, params) = parse_path_argument("to: b@example.com", "TO:").unwrap();
+        assert_eq!(path, "b@example.com");
+        assert!(params.is_empty());
+
+        assert_eq!(parse_path_argument("FROM:<>", "FROM:").unwrap().0, "");
+        assert!(parse_path_argument("FROM:<a@example.com", "FROM:").is_none());
+        assert!(parse_path_argument("TO:<a@example.com>", "FROM:").is_none());
+    }
+
     #[tokio::test]
     async fn test_handle_smtp_client_commands() {
         let state = create_test_state();
@@ -709,7 +2848,13 @@ mod tests {
         let state_clone = state.clone();
         let handle_task = tokio::spawn(async move {
             let (stream, client_addr) = listener.accept().await.unwrap();
-            handle_smtp_client(stream, client_addr, state_clone).await
+            handle_smtp_client(
+                stream,
+                client_addr,
+                state_clone,
+                Arc::new(SmtpConfig::default()),
+            )
+            .await
         });
 
         // Connect to the server
@@ -785,7 +2930,7 @@ mod tests {
         // Start the SMTP server in a separate task
         let state_clone = state.clone();
         let server_task = tokio::spawn(async move {
-            start_smtp_server(state_clone, port).await;
+            start_smtp_server(state_clone, port, Arc::new(SmtpConfig::default())).await;
         });
 
         // Give the server a moment to start
@@ -0,0 +1,656 @@
+//! Storage backends for captured emails
+//!
+//! The in-memory backend keeps everything in a vector and is lost on restart.
+//! The directory backend instead writes each email to its own directory, so captured
+//! mail survives restarts, and keeps only the metadata in memory:
+//!
+//! ```text
+//! <data-dir>/<email-id>/email.json          metadata (the API representation)
+//! <data-dir>/<email-id>/message.eml         raw message source as received
+//! <data-dir>/<email-id>/attachments/<id>    attachment contents, including those of
+//!                                           attached messages
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::models::{Email, EmailSummary};
+
+/// Storage backend for captured emails
+pub trait Storage: Send + Sync {
+    /// Get all stored emails, oldest first
+    fn list(&self) -> Vec<Email>;
+
+    /// Get a single email by ID
+    fn get(&self, id: &str) -> Option<Email>;
+
+    /// Get summaries of the emails matching the predicate, oldest first, without copying
+    /// the emails themselves
+    fn summaries(&self, predicate: &dyn Fn(&Email) -> bool) -> Vec<EmailSummary>;
+
+    /// Get the most recently received email matching the predicate
+    fn find_last(&self, predicate: &dyn Fn(&Email) -> bool) -> Option<Email>;
+
+    /// Run the visitor over all stored emails, oldest first, without copying them
+    ///
+    /// The storage is locked while the visitor runs, so it must not call back into it.
+    fn visit(&self, visitor: &mut dyn FnMut(&[Email]));
+
+    /// Get the ID, receive time and size of every stored email, oldest first
+    fn usage(&self) -> Vec<StoredEmail>;
+
+    /// Get the raw message source of an email exactly as received
+    fn raw(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Store a new email together with its raw message source
+    fn insert(&self, email: Email, raw: &[u8]) -> io::Result<()>;
+
+    /// Delete a single email, returning whether it existed
+    fn delete(&self, id: &str) -> io::Result<bool>;
+
+    /// Delete every email matching the predicate, returning the deleted IDs
+    fn delete_where(&self, predicate: &dyn Fn(&Email) -> bool) -> Result<Vec<String>, DeleteError>;
+}
+
+/// Failure partway through deleting emails
+///
+/// Carries the IDs of the emails that were already deleted, so that callers can still
+/// account for them.
+#[derive(Debug)]
+pub struct DeleteError {
+    /// IDs of the emails deleted before the failure
+    pub deleted: Vec<String>,
+    /// Cause of the failure
+    pub error: io::Error,
+}
+
+impl fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (after deleting {} emails)",
+            self.error,
+            self.deleted.len()
+        )
+    }
+}
+
+impl std::error::Error for DeleteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<DeleteError> for io::Error {
+    fn from(e: DeleteError) -> Self {
+        io::Error::new(e.error.kind(), e)
+    }
+}
+
+/// ID, receive time and size of a stored email, used to apply retention limits
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEmail {
+    /// Email ID
+    pub id: String,
+    /// Timestamp when the email was received
+    pub received_at: DateTime<Utc>,
+    /// Bytes held for the email: the raw message plus its decoded attachments
+    pub size: usize,
+}
+
+impl From<&Email> for StoredEmail {
+    fn from(email: &Email) -> Self {
+        Self {
+            id: email.id.clone(),
+            received_at: email.received_at,
+            size: email.size + attachment_bytes(email),
+        }
+    }
+}
+
+/// Size of the decoded attachments of an email and its attached messages
+///
+/// Both backends keep these next to the raw message, so they count towards its size.
+fn attachment_bytes(email: &Email) -> usize {
+    let own: usize = email
+        .attachments
+        .iter()
+        .map(|attachment| attachment.size)
+        .sum();
+    own + email
+        .attached_messages
+        .iter()
+        .map(attachment_bytes)
+        .sum::<usize>()
+}
+
+/// Storage that keeps emails in memory only
+#[derive(Default)]
+pub struct MemoryStorage {
+    /// Stored emails, oldest first
+    emails: RwLock<Emails>,
+    /// Raw message sources by email ID
+    raw: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+/// Stored emails in order of arrival, with the position of each ID for lookups
+#[derive(Default)]
+struct Emails {
+    /// Stored emails, oldest first
+    list: Vec<Email>,
+    /// Position of each email in `list` by ID
+    positions: HashMap<String, usize>,
+}
+
+impl Emails {
+    /// Create the list from emails in order of arrival
+    fn new(list: Vec<Email>) -> Self {
+        let mut emails = Self {
+            list,
+            positions: HashMap::new(),
+        };
+        emails.reindex();
+        emails
+    }
+
+    /// Add a newly arrived email
+    fn push(&mut self, email: Email) {
+        self.positions.insert(email.id.clone(), self.list.len());
+        self.list.push(email);
+    }
+
+    /// Look up an email by ID
+    fn get(&self, id: &str) -> Option<&Email> {
+        self.positions.get(id).map(|&position| &self.list[position])
+    }
+
+    /// Rebuild the positions after emails were removed
+    fn reindex(&mut self) {
+        self.positions = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(position, email)| (email.id.clone(), position))
+            .collect();
+    }
+}
+
+impl MemoryStorage {
+    /// Create an in-memory storage holding the given emails, without their raw sources
+    pub fn new(emails: Vec<Email>) -> Self {
+        Self {
+            emails: RwLock::new(Emails::new(emails)),
+            raw: RwLock::default(),
+        }
+    }
+
+    /// Add an email without keeping its raw source
+    fn push(&self, email: Email) {
+        self.emails.write().unwrap().push(email);
+    }
+
+    /// IDs of the emails matching the predicate, oldest first
+    fn matching_ids(&self, predicate: &dyn Fn(&Email) -> bool) -> Vec<String> {
+        let emails = self.emails.read().unwrap();
+        emails
+            .list
+            .iter()
+            .filter(|e| predicate(e))
+            .map(|e| e.id.clone())
+            .collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self) -> Vec<Email> {
+        self.emails.read().unwrap().list.clone()
+    }
+
+    fn get(&self, id: &str) -> Option<Email> {
+        self.emails.read().unwrap().get(id).cloned()
+    }
+
+    fn summaries(&self, predicate: &dyn Fn(&Email) -> bool) -> Vec<EmailSummary> {
+        let emails = self.emails.read().unwrap();
+        emails
+            .list
+            .iter()
+            .filter(|e| predicate(e))
+            .map(EmailSummary::from)
+            .collect()
+    }
+
+    fn find_last(&self, predicate: &dyn Fn(&Email) -> bool) -> Option<Email> {
+        let emails = self.emails.read().unwrap();
+        emails.list.iter().rev().find(|e| predicate(e)).cloned()
+    }
+
+    fn visit(&self, visitor: &mut dyn FnMut(&[Email])) {
+        visitor(&self.emails.read().unwrap().list);
+    }
+
+    fn usage(&self) -> Vec<StoredEmail> {
+        let emails = self.emails.read().unwrap();
+        emails.list.iter().map(StoredEmail::from).collect()
+    }
+
+    fn raw(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.raw.read().unwrap().get(id).cloned())
+    }
+
+    fn insert(&self, email: Email, raw: &[u8]) -> io::Result<()> {
+        self.raw
+            .write()
+            .unwrap()
+            .insert(email.id.clone(), raw.to_vec());
+        self.push(email);
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        Ok(!self.delete_where(&|e| e.id == id)?.is_empty())
+    }
+
+    fn delete_where(&self, predicate: &dyn Fn(&Email) -> bool) -> Result<Vec<String>, DeleteError> {
+        let mut emails = self.emails.write().unwrap();
+        let mut deleted = Vec::new();
+        emails.list.retain(|e| {
+            if predicate(e) {
+                deleted.push(e.id.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if !deleted.is_empty() {
+            emails.reindex();
+        }
+
+        let mut raw = self.raw.write().unwrap();
+        for id in &deleted {
+            raw.remove(id);
+        }
+        Ok(deleted)
+    }
+}
+
+/// Storage that persists every email to a directory on disk
+///
+/// The metadata of all emails is loaded into memory on startup and served from there;
+/// the directory is written on every change. Attachment contents and raw message
+/// sources are only kept on disk and read on demand, when a single email is requested.
+pub struct DirectoryStorage {
+    /// Root directory holding one subdirectory per email
+    dir: PathBuf,
+    /// In-memory copy of the stored emails, without attachment contents
+    cache: MemoryStorage,
+}
+
+/// Name of the metadata file inside an email directory
+const METADATA_FILE: &str = "email.json";
+/// Name of the raw message file inside an email directory
+const MESSAGE_FILE: &str = "message.eml";
+/// Name of the attachment directory inside an email directory
+const ATTACHMENTS_DIR: &str = "attachments";
+
+impl DirectoryStorage {
+    /// Open (or create) a storage directory and load the emails stored in it
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut emails = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            // Skip partially written or deleted emails and anything that is not an email directory
+            let is_hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !path.is_dir() || is_hidden {
+                continue;
+            }
+            match load_metadata(&path) {
+                Ok(email) => emails.push(email),
+                Err(e) => warn!("Skipping unreadable email in {}: {}", path.display(), e),
+            }
+        }
+        emails.sort_by_key(|e| e.received_at);
+        info!("Loaded {} emails from {}", emails.len(), dir.display());
+
+        Ok(Self {
+            dir,
+            cache: MemoryStorage::new(emails),
+        })
+    }
+
+    /// Directory of a single email
+    ///
+    /// IDs are generated by MailHits, but are still checked so that an ID taken from
+    /// a request can never point outside the storage directory.
+    fn email_dir(&self, id: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid email id: {}", id),
+            ));
+        }
+        Ok(self.dir.join(id))
+    }
+
+    /// Read the attachment contents of an email from disk
+    ///
+    /// The email is still returned when they cannot be read; its attachments then have no data.
+    fn with_attachments(&self, mut email: Email) -> Email {
+        let dir = match self.email_dir(&email.id) {
+            Ok(dir) => dir.join(ATTACHMENTS_DIR),
+            Err(_) => return email,
+        };
+        if let Err(e) = read_attachments(&dir, &mut email) {
+            warn!("Failed to read attachments of email {}: {}", email.id, e);
+        }
+        email
+    }
+
+    /// Remove the directory of an email
+    ///
+    /// The directory is renamed to a hidden one first, which is skipped on startup, so that
+    /// a failure never leaves a half-deleted email behind.
+    fn remove_email_dir(&self, id: &str) -> io::Result<()> {
+        let trash = self.dir.join(format!(".{}.deleted", id));
+        match fs::rename(self.email_dir(id)?, &trash) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+        if let Err(e) = fs::remove_dir_all(&trash) {
+            warn!("Failed to remove {}: {}", trash.display(), e);
+        }
+        Ok(())
+    }
+}
+
+/// Read the metadata of an email from its directory, without attachment contents
+fn load_metadata(path: &Path) -> io::Result<Email> {
+    let metadata = fs::read(path.join(METADATA_FILE))?;
+    Ok(serde_json::from_slice(&metadata)?)
+}
+
+/// Read the attachment contents of an email and its attached messages
+fn read_attachments(dir: &Path, email: &mut Email) -> io::Result<()> {
+    for attachment in email.attachments.iter_mut() {
+        attachment.data = Some(fs::read(dir.join(&attachment.id))?);
+    }
+    for child in email.attached_messages.iter_mut() {
+        read_attachments(dir, child)?;
+    }
+    Ok(())
+}
+
+/// Write the attachment contents of an email and its attached messages
+fn write_attachments(dir: &Path, email: &Email) -> io::Result<()> {
+    for attachment in &email.attachments {
+        let data = attachment.data.as_deref().unwrap_or_default();
+        fs::write(dir.join(&attachment.id), data)?;
+    }
+    for child in &email.attached_messages {
+        write_attachments(dir, child)?;
+    }
+    Ok(())
+}
+
+/// Drop the attachment contents of an email and its attached messages
+fn strip_attachments(email: &mut Email) {
+    for attachment in email.attachments.iter_mut() {
+        attachment.data = None;
+    }
+    for child in email.attached_messages.iter_mut() {
+        strip_attachments(child);
+    }
+}
+
+impl Storage for DirectoryStorage {
+    /// Reads the attachments of every email, so this is meant for startup only
+    fn list(&self) -> Vec<Email> {
+        self.cache
+            .list()
+            .into_iter()
+            .map(|email| self.with_attachments(email))
+            .collect()
+    }
+
+    fn get(&self, id: &str) -> Option<Email> {
+        self.cache.get(id).map(|email| self.with_attachments(email))
+    }
+
+    fn summaries(&self, predicate: &dyn Fn(&Email) -> bool) -> Vec<EmailSummary> {
+        self.cache.summaries(predicate)
+    }
+
+    fn find_last(&self, predicate: &dyn Fn(&Email) -> bool) -> Option<Email> {
+        self.cache
+            .find_last(predicate)
+            .map(|email| self.with_attachments(email))
+    }
+
+    /// The visited emails have no attachment contents
+    fn visit(&self, visitor: &mut dyn FnMut(&[Email])) {
+        self.cache.visit(visitor)
+    }
+
+    fn usage(&self) -> Vec<StoredEmail> {
+        self.cache.usage()
+    }
+
+    fn raw(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        if self.cache.get(id).is_none() {
+            return Ok(None);
+        }
+        match fs::read(self.email_dir(id)?.join(MESSAGE_FILE)) {
+            Ok(raw) => Ok(Some(raw)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn insert(&self, mut email: Email, raw: &[u8]) -> io::Result<()> {
+        let target = self.email_dir(&email.id)?;
+
+        // Write into a hidden directory first and rename it, so that a crash never
+        // leaves a half-written email behind
+        let staging = self.dir.join(format!(".{}.tmp", email.id));
+        fs::create_dir_all(staging.join(ATTACHMENTS_DIR))?;
+        fs::write(staging.join(MESSAGE_FILE), raw)?;
+        write_attachments(&staging.join(ATTACHMENTS_DIR), &email)?;
+        fs::write(staging.join(METADATA_FILE), serde_json::to_vec(&email)?)?;
+        fs::rename(&staging, &target)?;
+
+        strip_attachments(&mut email);
+        self.cache.push(email);
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        Ok(!self.delete_where(&|e| e.id == id)?.is_empty())
+    }
+
+    /// Emails are only dropped from memory once their directory is gone, so a failure
+    /// leaves the remaining emails both listed and on disk.
+    fn delete_where(&self, predicate: &dyn Fn(&Email) -> bool) -> Result<Vec<String>, DeleteError> {
+        let mut deleted = Vec::new();
+        let mut failure = None;
+        for id in self.cache.matching_ids(predicate) {
+            if let Err(e) = self.remove_email_dir(&id) {
+                failure = Some(e);
+                break;
+            }
+            deleted.push(id);
+        }
+
+        // Removing from memory cannot fail, so this returns exactly the removed IDs
+        let removed: HashSet<&str> = deleted.iter().map(String::as_str).collect();
+        let deleted = self
+            .cache
+            .delete_where(&|e| removed.contains(e.id.as_str()))?;
+        match failure {
+            Some(error) => Err(DeleteError { deleted, error }),
+            None => Ok(deleted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Attachment;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    // Helper function to create a test email
+    fn create_test_email(subject: &str) -> Email {
+        Email {
+            id: Uuid::new_v4().to_string(),
+            received_at: Utc::now(),
+            subject: subject.to_string(),
+            text_body: Some("body".to_string()),
+            attachments: vec![Attachment {
+                id: Uuid::new_v4().to_string(),
+                filename: "test.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                size: 4,
+                data: Some(b"test".to_vec()),
+            }],
+            ..Default::default()
+        }
+    }
+
+    // Helper function to create an empty temporary directory
+    fn create_test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("mailhits-storage-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::default();
+        let first = create_test_email("first");
+        let second = create_test_email("second");
+        storage.insert(first.clone(), b"first raw").unwrap();
+        storage.insert(second.clone(), b"second raw").unwrap();
+
+        assert_eq!(storage.list().len(), 2);
+        assert_eq!(storage.get(&second.id).unwrap().subject, "second");
+        let summaries = storage.summaries(&|e| e.subject == "second");
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, second.id);
+        assert_eq!(storage.find_last(&|_| true).unwrap().id, second.id);
+        assert!(storage.find_last(&|e| e.subject == "third").is_none());
+        let mut visited = Vec::new();
+        storage.visit(&mut |emails| visited = emails.iter().map(|e| e.id.clone()).collect());
+        assert_eq!(visited, vec![first.id.clone(), second.id.clone()]);
+        assert_eq!(storage.usage()[1], StoredEmail::from(&second));
+        // The raw message and the decoded attachment are both held
+        assert_eq!(storage.usage()[1].size, second.size + 4);
+        assert_eq!(storage.raw(&first.id).unwrap().unwrap(), b"first raw");
+
+        assert!(storage.delete(&first.id).unwrap());
+        assert!(!storage.delete(&first.id).unwrap());
+        assert!(storage.get(&first.id).is_none());
+        assert_eq!(storage.get(&second.id).unwrap().subject, "second");
+        assert!(storage.raw(&first.id).unwrap().is_none());
+        assert_eq!(storage.delete_where(&|_| true).unwrap(), vec![second.id]);
+        assert!(storage.list().is_empty());
+    }
+
+    #[test]
+    fn test_directory_storage_survives_reopen() {
+        let dir = create_test_dir();
+        let first = create_test_email("first");
+        let mut second = create_test_email("second");
+        second
+            .attached_messages
+            .push(create_test_email("forwarded"));
+
+        {
+            let storage = DirectoryStorage::open(&dir).unwrap();
+            storage
+                .insert(first.clone(), b"Subject: first\r\n\r\nbody")
+                .unwrap();
+            storage
+                .insert(second.clone(), b"Subject: second\r\n\r\nbody")
+                .unwrap();
+            assert!(storage.delete(&first.id).unwrap());
+        }
+
+        let storage = DirectoryStorage::open(&dir).unwrap();
+        // Attachment contents stay on disk until an email is requested
+        let cached = storage.cache.get(&second.id).unwrap();
+        assert!(cached.attachments[0].data.is_none());
+        assert_eq!(
+            storage.get(&second.id).unwrap().attachments[0]
+                .data
+                .as_deref(),
+            Some(&b"test"[..])
+        );
+        let emails = storage.list();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].id, second.id);
+        assert_eq!(emails[0].attachments[0].data.as_deref(), Some(&b"test"[..]));
+        assert_eq!(
+            emails[0].attached_messages[0].attachments[0]
+                .data
+                .as_deref(),
+            Some(&b"test"[..])
+        );
+        assert_eq!(
+            storage.raw(&second.id).unwrap().unwrap(),
+            b"Subject: second\r\n\r\nbody"
+        );
+        assert!(storage.raw(&first.id).unwrap().is_none());
+
+        storage.delete_where(&|_| true).unwrap();
+        assert!(DirectoryStorage::open(&dir).unwrap().list().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory_storage_rejects_invalid_ids() {
+        let dir = create_test_dir();
+        let storage = DirectoryStorage::open(&dir).unwrap();
+
+        let mut email = create_test_email("invalid");
+        email.id = "../escape".to_string();
+        assert!(storage.insert(email, b"raw").is_err());
+        assert!(storage.list().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory_storage_failed_delete_keeps_emails() {
+        let dir = create_test_dir();
+        let storage = DirectoryStorage::open(&dir).unwrap();
+        let first = create_test_email("first");
+        let second = create_test_email("second");
+        storage.insert(first.clone(), b"raw").unwrap();
+        // An email whose directory can never be removed
+        let mut broken = create_test_email("broken");
+        broken.id = "../broken".to_string();
+        storage.cache.push(broken);
+        storage.insert(second.clone(), b"raw").unwrap();
+
+        let error = storage.delete_where(&|_| true).unwrap_err();
+        assert_eq!(error.deleted, vec![first.id.clone()]);
+        assert!(storage.get(&first.id).is_none());
+        let ids: Vec<String> = storage.list().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec!["../broken".to_string(), second.id.clone()]);
+        assert!(storage.raw(&second.id).unwrap().is_some());
+
+        let reopened = DirectoryStorage::open(&dir).unwrap();
+        assert_eq!(reopened.list().len(), 1);
+        assert_eq!(reopened.list()[0].id, second.id);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+//! Message threading
+//!
+//! Emails are grouped into conversations through their `Message-ID`, `In-Reply-To` and
+//! `References` headers. Each email is given a thread ID when it is stored: it joins the
+//! thread of a stored email it replies to or that replies to it, and otherwise starts a
+//! thread of its own, identified by its email ID.
+//!
+//! Thread IDs never change once assigned, so two existing threads are not merged when a
+//! later email refers to messages in both: it joins the thread of its closest ancestor.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::models::{Email, ThreadSummary};
+
+/// Index of the message IDs of the stored emails, used to thread new emails
+///
+/// Finding the thread of a new email and adding it to the index happen under one lock, so
+/// that an email and a reply to it arriving at the same time always end up in one thread.
+#[derive(Debug, Default)]
+pub struct ThreadIndex {
+    inner: Mutex<Threads>,
+}
+
+/// Indexed message IDs and threads, guarded by the lock of [`ThreadIndex`]
+#[derive(Debug, Default)]
+struct Threads {
+    /// Message ID, then the ID and thread ID of each email with it, oldest first
+    messages: HashMap<String, Vec<(String, String)>>,
+    /// Message ID, then the ID and thread ID of each email replying to or referencing it,
+    /// oldest first
+    replies: HashMap<String, Vec<(String, String)>>,
+    /// Message IDs of each email and those it refers to, to remove it again
+    emails: HashMap<String, (Option<String>, Vec<String>)>,
+    /// Thread ID, then the IDs of its emails in the order they were stored
+    members: HashMap<String, Vec<String>>,
+    /// Thread ID of each email, to remove it from its thread again
+    email_threads: HashMap<String, String>,
+}
+
+impl ThreadIndex {
+    /// Create an index over the given emails, oldest first
+    pub fn new<'a>(emails: impl IntoIterator<Item = &'a Email>) -> Self {
+        let index = Self::default();
+        {
+            let mut threads = index.inner.lock().unwrap();
+            for email in emails {
+                threads.insert(email, thread_id(email));
+            }
+        }
+        index
+    }
+
+    /// Set the thread ID of a new email and add it to the index
+    pub fn assign(&self, email: &mut Email) {
+        let mut threads = self.inner.lock().unwrap();
+        email.thread_id = threads.find_thread(email);
+        threads.insert(email, &email.thread_id);
+    }
+
+    /// Remove an email from the index
+    pub fn remove(&self, id: &str) {
+        self.inner.lock().unwrap().remove(id);
+    }
+
+    /// Get the IDs of the emails in a thread, in the order they were stored
+    pub fn members(&self, thread_id: &str) -> Vec<String> {
+        let threads = self.inner.lock().unwrap();
+        threads.members.get(thread_id).cloned().unwrap_or_default()
+    }
+}
+
+impl Threads {
+    /// Find the thread a new email belongs to among the indexed emails
+    ///
+    /// The closest ancestor wins: the `In-Reply-To` message first, then the `References`
+    /// from the most recent to the root. Failing that, a stored reply to the email (which
+    /// arrived out of order) brings it into that reply's thread.
+    fn find_thread(&self, email: &Email) -> String {
+        let first = |emails: Option<&Vec<(String, String)>>| {
+            emails
+                .and_then(|emails| emails.first())
+                .map(|(_, thread_id)| thread_id.clone())
+        };
+        let ancestor = email
+            .in_reply_to
+            .iter()
+            .chain(email.references.iter().rev())
+            .find_map(|id| first(self.messages.get(id)));
+        let reply = || first(self.replies.get(email.message_id.as_ref()?));
+
+        ancestor.or_else(reply).unwrap_or_else(|| email.id.clone())
+    }
+
+    /// Add an email that belongs to the given thread
+    fn insert(&mut self, email: &Email, thread_id: &str) {
+        let entry = (email.id.clone(), thread_id.to_string());
+        if let Some(message_id) = &email.message_id {
+            self.messages
+                .entry(message_id.clone())
+                .or_default()
+                .push(entry.clone());
+        }
+        let mut referenced: Vec<String> = email
+            .in_reply_to
+            .iter()
+            .chain(&email.references)
+            .cloned()
+            .collect();
+        referenced.sort();
+        referenced.dedup();
+        for id in &referenced {
+            self.replies
+                .entry(id.clone())
+                .or_default()
+                .push(entry.clone());
+        }
+        self.emails
+            .insert(email.id.clone(), (email.message_id.clone(), referenced));
+        self.members
+            .entry(thread_id.to_string())
+            .or_default()
+            .push(email.id.clone());
+        self.email_threads
+            .insert(email.id.clone(), thread_id.to_string());
+    }
+
+    /// Remove an email, dropping the entries left empty
+    fn remove(&mut self, id: &str) {
+        let Some((message_id, referenced)) = self.emails.remove(id) else {
+            return;
+        };
+        if let Some(key) = message_id {
+            remove_entry(&mut self.messages, &key, id);
+        }
+        for key in referenced {
+            remove_entry(&mut self.replies, &key, id);
+        }
+        if let Some(thread_id) = self.email_threads.remove(id)
+            && let Some(members) = self.members.get_mut(&thread_id)
+        {
+            members.retain(|member| member != id);
+            if members.is_empty() {
+                self.members.remove(&thread_id);
+            }
+        }
+    }
+}
+
+/// Remove the entry of an email from the list under a message ID
+fn remove_entry(map: &mut HashMap<String, Vec<(String, String)>>, key: &str, id: &str) {
+    if let Some(emails) = map.get_mut(key) {
+        emails.retain(|(email_id, _)| email_id != id);
+        if emails.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+/// Thread ID of a stored email
+///
+/// Emails stored before threading was introduced have none and form a thread on their own.
+pub fn thread_id(email: &Email) -> &str {
+    if email.thread_id.is_empty() {
+        &email.id
+    } else {
+        &email.thread_id
+    }
+}
+
+/// Summarize the threads of the given emails, most recently active first (then by ID), with
+/// the emails of each thread oldest first
+pub fn summarize_threads<'a>(emails: impl IntoIterator<Item = &'a Email>) -> Vec<ThreadSummary> {
+    let mut threads: HashMap<&str, Vec<&Email>> = HashMap::new();
+    for email in emails {
+        threads.entry(thread_id(email)).or_default().push(email);
+    }
+
+    let mut summaries: Vec<ThreadSummary> = threads
+        .into_iter()
+        .map(|(id, mut emails)| {
+            emails.sort_by_key(|email| email.received_at);
+            summarize(id, &emails)
+        })
+        .collect();
+    summaries.sort_by(|a, b| {
+        b.last_received_at
+            .cmp(&a.last_received_at)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    summaries
+}
+
+/// Summarize a thread from its emails, oldest first
+fn summarize(id: &str, emails: &[&Email]) -> ThreadSummary {
+    ThreadSummary {
+        id: id.to_string(),
+        subject: emails
+            .first()
+            .map(|email| email.subject.clone())
+            .unwrap_or_default(),
+        email_ids: emails.iter().map(|email| email.id.clone()).collect(),
+        first_received_at: emails
+            .first()
+            .map(|email| email.received_at)
+            .unwrap_or_default(),
+        last_received_at: last_received_at(emails).unwrap_or_default(),
+    }
+}
+
+/// When the latest of the emails was received
+fn last_received_at(emails: &[&Email]) -> Option<DateTime<Utc>> {
+    emails.iter().map(|email| email.received_at).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn create_test_email(
+        id: &str,
+        message_id: &str,
+        in_reply_to: &[&str],
+        references: &[&str],
+    ) -> Email {
+        Email {
+            id: id.to_string(),
+            received_at: Utc::now(),
+            subject: format!("Subject {}", id),
+            message_id: Some(message_id.to_string()),
+            in_reply_to: in_reply_to.iter().map(|id| id.to_string()).collect(),
+            references: references.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Assign a thread to each email in turn, as if they were stored in this order
+    fn store_all(emails: Vec<Email>) -> Vec<Email> {
+        let index = ThreadIndex::default();
+        let mut stored = Vec::new();
+        for mut email in emails {
+            index.assign(&mut email);
+            stored.push(email);
+        }
+        stored
+    }
+
+    #[test]
+    fn test_find_thread() {
+        let stored = store_all(vec![
+            create_test_email("1", "a@example.com", &[], &[]),
+            create_test_email("2", "b@example.com", &["a@example.com"], &["a@example.com"]),
+            // Only References, with the parent missing from the store
+            create_test_email(
+                "3",
+                "c@example.com",
+                &[],
+                &["a@example.com", "x@example.com"],
+            ),
+            create_test_email("4", "d@example.com", &[], &[]),
+        ]);
+
+        let threads: Vec<&str> = stored
+            .iter()
+            .map(|email| email.thread_id.as_str())
+            .collect();
+        assert_eq!(threads, vec!["1", "1", "1", "4"]);
+    }
+
+    #[test]
+    fn test_find_thread_reply_first() {
+        let stored = store_all(vec![
+            create_test_email("1", "b@example.com", &["a@example.com"], &["a@example.com"]),
+            create_test_email("2", "a@example.com", &[], &[]),
+        ]);
+
+        assert_eq!(stored[1].thread_id, "1");
+    }
+
+    #[test]
+    fn test_thread_index_remove() {
+        let mut emails = vec![
+            create_test_email("1", "a@example.com", &[], &[]),
+            create_test_email("2", "b@example.com", &["a@example.com"], &[]),
+        ];
+        emails[1].thread_id = "1".to_string();
+        let index = ThreadIndex::new(&emails);
+
+        // Still threaded through the remaining reply
+        index.remove("1");
+        let mut reply = create_test_email("3", "c@example.com", &["b@example.com"], &[]);
+        index.assign(&mut reply);
+        assert_eq!(reply.thread_id, "1");
+
+        assert_eq!(index.members("1"), vec!["2", "3"]);
+        index.remove("2");
+        index.remove("3");
+        assert!(index.members("1").is_empty());
+        let mut reply = create_test_email("4", "d@example.com", &["a@example.com"], &[]);
+        index.assign(&mut reply);
+        assert_eq!(reply.thread_id, "4");
+        assert_eq!(index.inner.lock().unwrap().messages.len(), 1);
+        assert_eq!(index.inner.lock().unwrap().replies.len(), 1);
+    }
+
+    #[test]
+    fn test_summarize_threads() {
+        let mut emails = store_all(vec![
+            create_test_email("1", "a@example.com", &[], &[]),
+            create_test_email("2", "b@example.com", &[], &[]),
+            create_test_email("3", "c@example.com", &["a@example.com"], &[]),
+        ]);
+        let now = Utc::now();
+        for (i, email) in emails.iter_mut().enumerate() {
+            email.received_at = now + Duration::seconds(i as i64);
+        }
+        // Stored before threading: a thread of its own
+        emails.push(create_test_email("4", "d@example.com", &[], &[]));
+        emails[3].received_at = now - Duration::seconds(1);
+
+        let summaries = summarize_threads(&emails);
+        let ids: Vec<&str> = summaries
+            .iter()
+            .map(|summary| summary.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["1", "2", "4"]);
+        assert_eq!(summaries[0].email_ids, vec!["1", "3"]);
+        assert_eq!(summaries[0].subject, "Subject 1");
+        assert_eq!(summaries[0].first_received_at, now);
+        assert_eq!(summaries[0].last_received_at, now + Duration::seconds(2));
+    }
+}
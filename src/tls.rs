@@ -0,0 +1,138 @@
+//! TLS support for the SMTP server
+//!
+//! Builds the acceptor used for STARTTLS and implicit TLS, either from a user-provided certificate and key
+//! or from a self-signed certificate generated at startup.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::{self, ProtocolVersion, ServerConfig, ServerConnection};
+use tracing::info;
+
+use crate::models::TlsInfo;
+
+/// Host names included in the generated self-signed certificate
+const SELF_SIGNED_NAMES: [&str; 2] = ["localhost", "127.0.0.1"];
+
+/// Build a TLS acceptor for the SMTP server
+///
+/// Loads the PEM encoded certificate chain and private key when both paths are given,
+/// otherwise generates a self-signed certificate for `localhost`.
+pub fn build_tls_acceptor(
+    cert_path: Option<&Path>,
+    key_path: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let (certs, key) = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("Loading TLS certificate from {}", cert_path.display());
+            load_certificate(cert_path, key_path)?
+        }
+        (None, None) => {
+            info!("Generating self-signed TLS certificate");
+            generate_self_signed()?
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "both a TLS certificate and a TLS key must be provided",
+            ));
+        }
+    };
+
+    tls_acceptor(certs, key)
+}
+
+/// Create a TLS acceptor from a certificate chain and its private key
+pub fn tls_acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<TlsAcceptor> {
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Load a PEM encoded certificate chain and private key from disk
+fn load_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok((certs, key))
+}
+
+/// Generate a self-signed certificate valid for `localhost`
+pub fn generate_self_signed() -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>
+{
+    let names: Vec<String> = SELF_SIGNED_NAMES.iter().map(|s| s.to_string()).collect();
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    Ok((vec![certified.cert.der().clone()], key.into()))
+}
+
+/// Describe the TLS parameters negotiated on a server connection
+///
+/// `implicit` tells whether the handshake happened on connect (SMTPS) rather than after STARTTLS.
+pub fn tls_info(connection: &ServerConnection, implicit: bool) -> TlsInfo {
+    let version = match connection.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+        Some(other) => format!("{:?}", other),
+        None => "unknown".to_string(),
+    };
+    let cipher = connection
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    TlsInfo {
+        version,
+        cipher,
+        implicit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_tls_acceptor_self_signed() {
+        assert!(build_tls_acceptor(None, None).is_ok());
+    }
+
+    #[test]
+    fn test_build_tls_acceptor_requires_cert_and_key() {
+        let result = build_tls_acceptor(Some(Path::new("cert.pem")), None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_build_tls_acceptor_from_files() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("mailhits-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        let result = build_tls_acceptor(Some(&cert_path), Some(&key_path));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+    }
+}
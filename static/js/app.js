@@ -1,6 +1,15 @@
+// Summaries of the listed emails; full emails are fetched when selected
 let emails = [];
 let selectedEmailId = null;
-const socket = new WebSocket(`ws://${window.location.host}/ws`);
+
+// WebSocket protocol version understood by this client
+const PROTOCOL_VERSION = 1;
+
+// Mailbox selected through the ?mailbox= query parameter (all mailboxes when empty)
+let mailbox = new URLSearchParams(window.location.search).get('mailbox') || '';
+const mailboxQuery = () => mailbox ? `?mailbox=${encodeURIComponent(mailbox)}` : '';
+
+const socket = new WebSocket(`ws://${window.location.host}/ws${mailboxQuery()}`);
 
 // WebSocket event handlers
 socket.onopen = () => {
@@ -8,13 +17,36 @@ socket.onopen = () => {
 };
 
 socket.onmessage = (event) => {
-    const email = JSON.parse(event.data);
-    // Check if this email already exists
-    const existingIndex = emails.findIndex(e => e.id === email.id);
-    if (existingIndex >= 0) {
-        emails[existingIndex] = email;
-    } else {
-        emails.unshift(email); // Add to the beginning
+    const message = JSON.parse(event.data);
+    if (message.version !== PROTOCOL_VERSION) {
+        console.warn(`Unsupported WebSocket protocol version ${message.version}`);
+        return;
+    }
+
+    if (message.type === 'snapshot') {
+        // Sent on connect and after each subscription change
+        emails = message.emails.reverse();
+        if (selectedEmailId && !emails.some(e => e.id === selectedEmailId.split('.')[0])) {
+            clearSelection();
+        }
+    } else if (message.type === 'created') {
+        const email = message.email;
+        // Check if this email already exists
+        const existingIndex = emails.findIndex(e => e.id === email.id);
+        if (existingIndex >= 0) {
+            emails[existingIndex] = email;
+        } else {
+            emails.unshift(email); // Add to the beginning
+        }
+    } else if (message.type === 'deleted' || message.type === 'cleared') {
+        // Deleted by a client or evicted by the retention limits
+        const ids = message.type === 'deleted' ? [message.id] : message.ids;
+        emails = emails.filter(e => !ids.includes(e.id));
+        if (selectedEmailId && ids.includes(selectedEmailId.split('.')[0])) {
+            clearSelection();
+        }
+    } else if (message.type === 'error') {
+        console.error('WebSocket error message:', message.message);
     }
     renderEmailList();
 };
@@ -27,14 +59,33 @@ socket.onclose = () => {
     console.log('WebSocket connection closed');
 };
 
-// Fetch emails on load
-fetch('/api/emails')
+// Fetch mailboxes for the mailbox selector
+fetch('/api/mailboxes')
     .then(response => response.json())
-    .then(data => {
-        emails = data.sort((a, b) => new Date(b.received_at) - new Date(a.received_at));
-        renderEmailList();
+    .then(mailboxes => {
+        const select = document.getElementById('mailbox-select');
+        const names = mailboxes.map(m => m.name);
+        if (mailbox && !names.includes(mailbox)) {
+            names.push(mailbox);
+        }
+        names.forEach(name => {
+            const option = document.createElement('option');
+            option.value = name;
+            option.textContent = name;
+            select.appendChild(option);
+        });
+        select.value = mailbox;
+        select.addEventListener('change', () => {
+            // Switch the subscription in place; the server answers with a new snapshot
+            mailbox = select.value;
+            history.replaceState(null, '', `${window.location.pathname}${mailboxQuery()}`);
+            socket.send(JSON.stringify({
+                type: 'subscribe',
+                mailboxes: mailbox ? [mailbox] : [],
+            }));
+        });
     })
-    .catch(error => console.error('Error fetching emails:', error));
+    .catch(error => console.error('Error fetching mailboxes:', error));
 
 // Render email list
 function renderEmailList() {
@@ -78,6 +129,13 @@ function renderEmailList() {
         to.textContent = `To: ${email.to.join(', ')}`;
         emailItem.appendChild(to);
 
+        if (email.snippet) {
+            const snippet = document.createElement('div');
+            snippet.className = 'email-snippet';
+            snippet.textContent = email.snippet;
+            emailItem.appendChild(snippet);
+        }
+
         emailItem.addEventListener('click', () => {
             selectEmail(email.id);
         });
@@ -86,44 +144,132 @@ function renderEmailList() {
     });
 }
 
-// Select an email
+// Select an email, including attached messages (`<id>.1`, `<id>.1.2`, ...)
 function selectEmail(id) {
     selectedEmailId = id;
-    const email = emails.find(e => e.id === id);
-
-    if (!email) return;
 
     // Update UI
     document.querySelectorAll('.email-item').forEach(item => {
-        item.classList.toggle('selected', item.dataset.id === id);
+        item.classList.toggle('selected', item.dataset.id === id.split('.')[0]);
     });
 
+    // The list only holds summaries, so fetch the full email
+    fetch(`/api/emails/${encodeURIComponent(id)}`)
+        .then(response => response.ok ? response.json() : null)
+        .then(email => {
+            if (email && selectedEmailId === id) {
+                showEmail(email);
+            }
+        })
+        .catch(error => console.error('Error fetching email:', error));
+}
+
+// Show the details of a full email
+function showEmail(email) {
     document.getElementById('no-email-selected').style.display = 'none';
     document.getElementById('email-details').style.display = 'block';
 
     // Fill email details
     document.getElementById('email-subject').textContent = email.subject;
-    document.getElementById('email-from').textContent = `From: ${email.from}`;
-    document.getElementById('email-to').textContent = `To: ${email.to.join(', ')}`;
+    // Prefer the header addresses, falling back to the SMTP envelope
+    const addresses = email.addresses || {};
+    const from = formatAddresses(addresses.from) || email.from;
+    const to = formatAddresses(addresses.to) || email.to.join(', ');
+    const cc = formatAddresses(addresses.cc);
+    document.getElementById('email-from').textContent = `From: ${from}`;
+    document.getElementById('email-to').textContent = `To: ${to}`;
+    document.getElementById('email-cc').textContent = cc ? `Cc: ${cc}` : '';
+    const bcc = (email.bcc || []).join(', ');
+    document.getElementById('email-bcc').textContent = bcc ? `Bcc (hidden): ${bcc}` : '';
+
+    // Summarize the delivery status of each recipient of a DSN
+    const dsn = email.dsn
+        ? email.dsn.recipients.map(recipient => [
+            recipient.final_recipient,
+            recipient.action,
+            recipient.status,
+            recipient.diagnostic_code && `(${recipient.diagnostic_code})`,
+        ].filter(Boolean).join(' ')).join('; ')
+        : '';
+    document.getElementById('email-dsn').textContent = dsn ? `Delivery status: ${dsn}` : '';
+
+    // Summarize the events of a calendar invitation or cancellation
+    const calendar = email.calendar
+        ? email.calendar.events.map(event => [
+            event.summary,
+            event.start && [event.start.value, event.start.tzid].filter(Boolean).join(' '),
+            event.attendees.length && `(${event.attendees.map(attendee => attendee.address).join(', ')})`,
+        ].filter(Boolean).join(' ')).join('; ')
+        : '';
+    document.getElementById('email-calendar').textContent = email.calendar
+        ? `Calendar ${email.calendar.method || ''}: ${calendar}`
+        : '';
+
+    // Link attached messages back to the email they are attached to
+    const parentLink = document.getElementById('email-parent');
+    parentLink.innerHTML = '';
+    if (email.parent_id) {
+        const parent = emails.find(e => e.id === email.parent_id);
+        const link = document.createElement('a');
+        link.href = '#';
+        link.textContent = parent ? parent.subject : email.parent_id;
+        link.addEventListener('click', event => {
+            event.preventDefault();
+            selectEmail(email.parent_id);
+        });
+        parentLink.append('Attached to: ', link);
+    }
 
     const date = new Date(email.received_at);
     document.getElementById('email-time').textContent = `Received: ${date.toLocaleString()}`;
 
     // Fill content tabs
+    // Load the sanitized HTML, which resolves the cid: references to inline images.
+    // Remote images stay blocked until requested, as loading them reveals that the email was opened
+    const htmlFrame = document.getElementById('html-frame');
+    const loadRemoteImages = document.getElementById('load-remote-images');
     if (email.html_body) {
-        const htmlFrame = document.getElementById('html-frame');
-        htmlFrame.srcdoc = email.html_body;
+        htmlFrame.removeAttribute('srcdoc');
+        htmlFrame.src = `/api/emails/${email.id}/html`;
+        loadRemoteImages.style.display = 'inline-block';
+        loadRemoteImages.onclick = () => {
+            htmlFrame.src = `/api/emails/${email.id}/html?remote_images=true`;
+            loadRemoteImages.style.display = 'none';
+        };
     } else {
-        document.getElementById('html-frame').srcdoc = '<p>No HTML content</p>';
+        htmlFrame.srcdoc = '<p>No HTML content</p>';
+        loadRemoteImages.style.display = 'none';
     }
 
     document.getElementById('text-content').textContent = email.text_body || 'No text content';
 
-    const headersText = Object.entries(email.headers)
-        .map(([key, value]) => `${key}: ${value}`)
-        .join('\n');
-
-    document.getElementById('headers-content').innerHTML = headersText;
+    // Show the headers in message order and as written, when available
+    const headersText = email.header_list && email.header_list.length > 0
+        ? email.header_list.map(header => `${header.name}:${header.raw}`).join('\n')
+        : Object.entries(email.headers).map(([key, value]) => `${key}: ${value}`).join('\n');
+
+    document.getElementById('headers-content').textContent = headersText;
+
+    // Fill raw source tab
+    const rawContent = document.getElementById('raw-content');
+    rawContent.textContent = 'Loading...';
+    document.getElementById('raw-download').href = `/api/emails/${email.id}/raw`;
+    fetch(`/api/emails/${email.id}/raw`)
+        .then(response => response.ok ? response.text() : 'Raw source not available')
+        .then(text => {
+            if (selectedEmailId === email.id) {
+                rawContent.textContent = text;
+            }
+        })
+        .catch(error => console.error('Error fetching raw source:', error));
+
+    // Fill MIME structure tab
+    const structureTree = document.getElementById('structure-tree');
+    structureTree.innerHTML = '';
+    document.getElementById('no-structure').style.display = email.mime ? 'none' : 'block';
+    if (email.mime) {
+        structureTree.appendChild(createMimeNode(email.id, email.mime));
+    }
 
     // Fill attachments tab
     const attachmentsItems = document.getElementById('attachments-items');
@@ -131,9 +277,8 @@ function selectEmail(id) {
 
     attachmentsItems.innerHTML = '';
 
+    noAttachments.style.display = 'none';
     if (email.attachments && email.attachments.length > 0) {
-        noAttachments.style.display = 'none';
-
         email.attachments.forEach(attachment => {
             const li = document.createElement('li');
             li.className = 'attachment-item';
@@ -165,11 +310,56 @@ function selectEmail(id) {
             li.appendChild(link);
             attachmentsItems.appendChild(li);
         });
-    } else {
+    }
+
+    // Attached messages open in place of the email
+    (email.attached_messages || []).forEach(child => {
+        const li = document.createElement('li');
+        li.className = 'attachment-item';
+
+        const link = document.createElement('a');
+        link.href = '#';
+        link.className = 'attachment-link';
+        link.addEventListener('click', event => {
+            event.preventDefault();
+            selectEmail(child.id);
+        });
+
+        const icon = document.createElement('span');
+        icon.className = 'icon';
+        icon.innerHTML = '&#9993;';
+        link.appendChild(icon);
+
+        const info = document.createElement('div');
+        info.className = 'attachment-info';
+
+        const name = document.createElement('div');
+        name.className = 'attachment-name';
+        name.textContent = child.subject;
+        info.appendChild(name);
+
+        const meta = document.createElement('div');
+        meta.className = 'attachment-meta';
+        meta.textContent = `Attached message, ${formatFileSize(child.size)}`;
+        info.appendChild(meta);
+
+        link.appendChild(info);
+        li.appendChild(link);
+        attachmentsItems.appendChild(li);
+    });
+
+    if (attachmentsItems.children.length === 0) {
         noAttachments.style.display = 'block';
     }
 }
 
+// Clear the selected email
+function clearSelection() {
+    selectedEmailId = null;
+    document.getElementById('no-email-selected').style.display = 'flex';
+    document.getElementById('email-details').style.display = 'none';
+}
+
 // Tab switching
 document.querySelectorAll('.tab-button').forEach(button => {
     button.addEventListener('click', () => {
@@ -186,6 +376,64 @@ document.querySelectorAll('.tab-button').forEach(button => {
     });
 });
 
+// Format a list of header addresses as `Name <address>`, grouping members of RFC 5322 groups
+function formatAddresses(list) {
+    if (!list || list.length === 0) return '';
+
+    const parts = [];
+    let currentGroup = null;
+    list.forEach(entry => {
+        const mailbox = entry.name && entry.address
+            ? `${entry.name} <${entry.address}>`
+            : (entry.address || entry.name || '');
+        if (entry.group) {
+            if (!currentGroup || currentGroup.name !== entry.group) {
+                currentGroup = { name: entry.group, members: [] };
+                parts.push(currentGroup);
+            }
+            if (mailbox) currentGroup.members.push(mailbox);
+        } else {
+            currentGroup = null;
+            parts.push(mailbox);
+        }
+    });
+
+    return parts
+        .map(part => typeof part === 'string' ? part : `${part.name}: ${part.members.join(', ')};`)
+        .join(', ');
+}
+
+// Create a list item for a MIME part and its children, linking to the part content
+function createMimeNode(emailId, part) {
+    const li = document.createElement('li');
+
+    const link = document.createElement('a');
+    link.href = `/api/emails/${emailId}/parts/${part.path}`;
+    link.target = '_blank';
+    link.textContent = `${part.path} ${part.content_type}`;
+    li.appendChild(link);
+
+    const details = [
+        part.charset && `charset=${part.charset}`,
+        part.transfer_encoding,
+        part.disposition,
+        part.filename,
+        part.content_id && `<${part.content_id}>`,
+        formatFileSize(part.size),
+    ].filter(Boolean);
+    const meta = document.createElement('span');
+    meta.className = 'structure-meta';
+    meta.textContent = ` ${details.join(', ')}`;
+    li.appendChild(meta);
+
+    if (part.children && part.children.length > 0) {
+        const ul = document.createElement('ul');
+        part.children.forEach(child => ul.appendChild(createMimeNode(emailId, child)));
+        li.appendChild(ul);
+    }
+    return li;
+}
+
 // Format file size to human readable format
 function formatFileSize(bytes) {
     if (bytes === 0) return '0 Bytes';
@@ -200,15 +448,13 @@ function formatFileSize(bytes) {
 // Clear all emails
 document.getElementById('clear-all').addEventListener('click', () => {
     if (confirm('Are you sure you want to delete all emails?')) {
-        fetch('/api/emails', {
+        fetch(`/api/emails${mailboxQuery()}`, {
             method: 'POST'
         })
         .then(() => {
             emails = [];
-            selectedEmailId = null;
+            clearSelection();
             renderEmailList();
-            document.getElementById('no-email-selected').style.display = 'flex';
-            document.getElementById('email-details').style.display = 'none';
         })
         .catch(error => console.error('Error clearing emails:', error));
     }